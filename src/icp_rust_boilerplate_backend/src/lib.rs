@@ -1,22 +1,119 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
+use candid::{Decode, Encode, Nat, Principal};
 use ic_cdk::api::time;
+use ic_cdk_timers::TimerId;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell};
+use sha2::{Digest, Sha256};
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::cmp::Reverse;
+use std::collections::BTreeSet;
+use std::ops::Bound;
+use std::{borrow::Cow, cell::RefCell, time::Duration};
 
 // Define memory and id cell types
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
+// Ledger and subaccount of a token gate
+type TokenAccount = (Principal, Option<Vec<u8>>);
+
+// Limits for condition trees, enforced at creation
+const MAX_CONDITION_DEPTH: usize = 8;
+const MAX_CONDITION_NODES: usize = 32;
+
+// Maximum number of due capsules processed per scheduler tick
+const UNLOCK_BATCH_SIZE: usize = 100;
+
+// Earth's radius in kilometers
+const EARTH_RADIUS_KM: f64 = 6371.0;
+// Bits per axis of geo index cells, about 0.3m of latitude at full precision
+const GEO_INDEX_BITS: u32 = 26;
+// Maximum number of cells scanned per location query
+const MAX_GEO_CELLS: u64 = 32;
+
+// Longest tag, in bytes after normalization, and most tags per capsule; tags are index keys
+const MAX_TAG_LENGTH: usize = 64;
+const MAX_TAGS: usize = 20;
+
+// Search terms: words of 2 to 32 bytes; the heaviest terms of a capsule are indexed,
+// capped so indexing a batch of unlocked capsules fits in one scheduler tick
+const MIN_TERM_LENGTH: usize = 2;
+const MAX_TERM_LENGTH: usize = 32;
+const MAX_INDEXED_TERMS: usize = 100;
+const MAX_QUERY_TERMS: usize = 8;
+
+// Page size cap for list endpoints
+const MAX_PAGE_SIZE: u32 = 100;
+// Maximum number of capsules examined per list call, so sparse filters stay within the instruction limit
+const MAX_PAGE_SCAN: usize = 5_000;
+// Encoded size of a page's items, kept well below the 2MB reply limit; a page always holds
+// at least one item, which fits on its own because a capsule is at most 1MB
+const MAX_PAGE_BYTES: usize = 1536 * 1024;
+
+// Layout version of stored capsules; bump it and add a migration whenever
+// a change can't be decoded from the previous layout
+const CAPSULE_SCHEMA_VERSION: u32 = 3;
+// Prefix of versioned capsule records, followed by the big-endian schema version
+const CAPSULE_ENVELOPE_MAGIC: &[u8; 4] = b"TCAP";
+// Capsules migrated per message by the background pass after an upgrade
+const MIGRATION_BATCH_SIZE: usize = 100;
+// Layout version of the unlock queue and secondary indexes; bump it whenever an index is
+// added or indexes more capsules, so the next upgrade rebuilds them from CAPSULE_STORAGE.
+// The rebuild only inserts keys, so an index whose keys change shape needs a new MemoryId
+const INDEX_SCHEMA_VERSION: u32 = 2;
+
+// Most viewers a content key can be wrapped for
+const MAX_WRAPPED_KEYS: usize = 100;
+// Most principals in a private capsule's or viewer condition's allow-list
+const MAX_ALLOWED_VIEWERS: usize = 1_000;
+
+// vetKD master key; "dfx_test_key" on a local replica, "test_key_1" or "key_1" on mainnet
+const VETKD_KEY_NAME: &str = "dfx_test_key";
+// Domain separator of capsule keys, so other canisters' derivations never collide with ours
+const VETKD_CONTEXT: &[u8] = b"time_capsule_v1";
+// Cycles attached to vetkd_derive_key, enough for the fee on the 34-node subnets that hold
+// test_key_1 and key_1; unused cycles are refunded
+const VETKD_DERIVE_KEY_CYCLES: u128 = 26_153_846_153;
+// Key derivations the canister pays for per caller and in total each hour, only for callers
+// a capsule names; callers that attach VETKD_DERIVE_KEY_CYCLES pay for their own and aren't counted
+const KEY_DERIVATION_WINDOW: u64 = 60 * 60 * 1_000_000_000;
+const MAX_KEY_DERIVATIONS_PER_CALLER: u32 = 10;
+const MAX_KEY_DERIVATIONS: u32 = 200;
+// Unused capsule ID reservations a principal can hold, and how long one stays valid, in nanoseconds
+const MAX_RESERVED_IDS: usize = 10;
+const RESERVATION_TTL: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+// Size of every blob chunk except the last, well below the ~2MB ingress limit
+const BLOB_CHUNK_SIZE: u64 = 1024 * 1024;
+// Largest blob accepted by begin_upload; finalize_upload hashes it in one message
+const MAX_BLOB_SIZE: u64 = 100 * 1024 * 1024;
+// Uploads no capsule references that a principal can have at once, finalized or not, and their combined size
+const MAX_PENDING_UPLOADS: usize = 10;
+const MAX_PENDING_UPLOAD_BYTES: u64 = 2 * MAX_BLOB_SIZE;
+// Time to finalize an upload and reference it from a capsule before it is discarded, in nanoseconds.
+// A blob released by an edit gets a fresh period
+const UPLOAD_TTL: u64 = 24 * 60 * 60 * 1_000_000_000;
+// Interval of the sweep that discards expired uploads and reservations, and records examined per sweep message
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const SWEEP_BATCH_SIZE: usize = 100;
+
+// How long a pass through unlock_capsule lets its caller read the capsule through queries, in
+// nanoseconds, and the unexpired passes a capsule can hold at once
+const VERIFIED_UNLOCK_TTL: u64 = 60 * 60 * 1_000_000_000;
+const MAX_VERIFIED_UNLOCKS: usize = 1_000;
+
+// Longest grace period in which a creator can edit or cancel a capsule, in nanoseconds
+const MAX_EDIT_GRACE_PERIOD: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+// Shortest check-in interval of a dead man's switch, in nanoseconds
+const MIN_CHECKIN_INTERVAL: u64 = 60 * 60 * 1_000_000_000;
+
 // Content types that can be stored in the time capsule
 #[derive(candid::CandidType, Clone, Serialize, Deserialize)]
 enum CapsuleContent {
     Text(String),
-    EncryptedMessage {
-        content: Vec<u8>,
-        public_key: String,
-    },
+    EncryptedMessage(EncryptedEnvelope),
     MediaReference {
         ipfs_hash: String,
         media_type: String,
@@ -25,6 +122,45 @@ enum CapsuleContent {
         parts: Vec<CapsuleContent>,
         title: String,
     },
+    // Chunked upload from begin_upload; size and hash must match the finalized blob
+    Blob {
+        blob_id: u64,
+        media_type: String,
+        size: u64,
+        sha256: Vec<u8>,
+    },
+    // SHA-256 of the salt and canonical content bytes (see commitment_hash), replaced by reveal_capsule after unlock
+    Committed {
+        commitment: Vec<u8>,
+    },
+}
+
+// Encrypted content, checked at creation so broken uploads are caught before unlock
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct EncryptedEnvelope {
+    algorithm: EncryptionAlgorithm,
+    recipient_public_key: Vec<u8>,
+    key_fingerprint: Vec<u8>, // SHA-256 of recipient_public_key
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,      // Including the AEAD tag
+    content_hash: Vec<u8>,    // SHA-256 of ciphertext
+    wrapped_keys: Option<Vec<WrappedKey>>, // Content key wrapped for each viewer; callers only see their own
+}
+
+// Content key of an envelope, encrypted to one viewer's public key
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct WrappedKey {
+    recipient: Principal,
+    key_fingerprint: Vec<u8>, // SHA-256 of the viewer's public key
+    wrapped_key: Vec<u8>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+enum EncryptionAlgorithm {
+    X25519XChaCha20Poly1305, // Raw 32-byte X25519 key, 24-byte nonce
+    P256Aes256Gcm,           // SEC1-encoded P-256 key, 12-byte nonce
+    VetKdIbe,                // IBE to the canister's vetKD public key for vetkd_input; no separate nonce
+    Unspecified,             // Migrated from the unstructured format; rejected for new content
 }
 
 // Access control for the capsule
@@ -32,25 +168,113 @@ enum CapsuleContent {
 enum AccessControl {
     Public,
     Private {
-        allowed_viewers: Vec<String>, // Principal IDs
+        allowed_viewers: Vec<Principal>, // Sorted and deduplicated, for binary search
     },
     Conditional {
-        condition_type: String,
-        condition_data: String, // Could be a smart contract address, oracle reference, etc.
+        condition: UnlockCondition,
     },
+    ConditionTree {
+        root: ConditionExpr,
+    },
+}
+
+// Boolean expression over unlock conditions
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum ConditionExpr {
+    Condition(UnlockCondition),
+    And(Vec<ConditionExpr>),
+    Or(Vec<ConditionExpr>),
+    Not(Box<ConditionExpr>),
+}
+
+// Conditions that can gate access to a capsule once it is unlocked
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum UnlockCondition {
+    TokenHolder(TokenGate),
+    NftHolder(NftGate),
+    GeoFence(GeoFence),
+    Quiz(QuizChallenge),
+    Timestamp { not_before: u64 },
+    Guardians(GuardianQuorum),
+    Viewers { allowed_viewers: Vec<Principal> }, // Sorted and deduplicated, for binary search
+}
+
+// Holds at least `min_balance` on an ICRC-1 ledger
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TokenGate {
+    ledger_canister_id: Principal,
+    min_balance: Nat,
+    subaccount: Option<Vec<u8>>, // Subaccount of the caller to check, defaults to the main account
+}
+
+// Holds at least `min_tokens` of an ICRC-7 collection
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct NftGate {
+    collection_canister_id: Principal,
+    min_tokens: u64,
+}
+
+// Caller reports a position within `radius_km` of the center
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct GeoFence {
+    latitude: f64,
+    longitude: f64,
+    radius_km: f64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct QuizChallenge {
+    questions: Vec<QuizQuestion>,
+    max_attempts: u32, // Per principal
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct QuizQuestion {
+    question: String,
+    salt: Vec<u8>,
+    answer_hash: Vec<u8>, // SHA-256 of salt bytes followed by the normalized answer
+}
+
+// At least `threshold` of the guardians approved the capsule
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct GuardianQuorum {
+    guardians: Vec<Principal>,
+    threshold: u32,
+}
+
+// Releases the capsule if its creator doesn't check in every `checkin_interval` nanoseconds.
+// A missed check-in first opens a `warning_period` in which the capsule is UnlockPending
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DeadManSwitch {
+    checkin_interval: u64,
+    warning_period: u64,
+}
+
+// Position reported by a caller when unlocking a geo-fenced capsule
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct GeoPoint {
+    latitude: f64,
+    longitude: f64,
 }
 
 // Main time capsule structure
 #[derive(candid::CandidType, Clone, Serialize, Deserialize)]
 struct TimeCapsule {
     id: u64,
-    creator: String, // Principal ID
+    creator: Principal,
     creation_date: u64,
     unlock_date: u64,
     content: CapsuleContent,
     access_control: AccessControl,
     metadata: CapsuleMetadata,
     status: CapsuleStatus,
+    editable_until: Option<u64>, // End of the creator's grace period, if any
+    unlock_date_locked: Option<bool>, // Forbids extend_unlock_date when true
+    early_unlock_guardians: Option<GuardianQuorum>, // Guardians who can open the capsule before unlock_date
+    early_unlocked_at: Option<u64>, // When guardians or the dead man's switch opened it before unlock_date
+    dead_man_switch: Option<DeadManSwitch>,
+    last_checkin: Option<u64>, // Creation or the creator's latest heartbeat_checkin
+    sealed_metadata: Option<Vec<MetadataField>>, // Metadata withheld from everyone but the creator until the time lock passes
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize)]
@@ -62,14 +286,34 @@ struct CapsuleMetadata {
     cultural_significance: Option<String>,
 }
 
+// Metadata fields that can be sealed along with the content
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
+enum MetadataField {
+    Title,
+    Description,
+    Tags,
+    Location,
+    CulturalSignificance,
+}
+
 #[derive(candid::CandidType, Clone, Serialize, Deserialize)]
 struct GeoLocation {
     latitude: f64,
     longitude: f64,
     location_name: String,
+    precision: Option<LocationPrecision>, // Precision shown to others until unlock; exact when unset
 }
 
+// Grid a sealed capsule's coordinates are snapped to before anyone else sees them
 #[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum LocationPrecision {
+    Exact,
+    Street,       // 0.001 degrees, about 100m
+    Neighborhood, // 0.01 degrees, about 1km
+    City,         // 0.1 degrees, about 10km
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq)]
 enum CapsuleStatus {
     Sealed,
     UnlockPending,
@@ -77,192 +321,5749 @@ enum CapsuleStatus {
     Archived,
 }
 
-// Payload for creating a new time capsule
+// ICRC-1 account as expected by `icrc1_balance_of` and `icrc7_balance_of`
 #[derive(candid::CandidType, Clone, Serialize, Deserialize)]
-struct CreateCapsulePayload {
-    content: CapsuleContent,
-    unlock_date: u64,
-    access_control: AccessControl,
-    metadata: CapsuleMetadata,
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
 }
 
-// Storage implementation
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
-    );
+// Facts gathered before evaluating conditions; queries evaluate with an empty context
+#[derive(Default)]
+struct ConditionContext {
+    location: Option<GeoPoint>,
+    token_balances: BTreeMap<TokenAccount, Result<Nat, String>>,
+    nft_balances: BTreeMap<Principal, Result<Nat, String>>,
+}
 
-    static CAPSULE_STORAGE: RefCell<StableBTreeMap<u64, TimeCapsule, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)))
-        )
-    );
+// Why a condition did not pass
+enum ConditionFailure {
+    Unmet(String),      // Evaluated and not satisfied
+    Unverified(String), // Could not be evaluated, e.g. needs unlock_capsule; never satisfies a NOT
+}
 
-    static ID_COUNTER: RefCell<Cell<u64, Memory>> = RefCell::new(
-        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))), 0)
-            .expect("Cannot create counter")
-    );
+// Quiz attempts of a principal for a capsule
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct QuizAttemptKey {
+    capsule_id: u64,
+    principal: Principal,
 }
 
-// Implementation for TimeCapsule
-impl Storable for TimeCapsule {
-    fn to_bytes(&self) -> Cow<[u8]> {
-        Cow::Owned(Encode!(self).unwrap())
-    }
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct QuizProgress {
+    attempts: u32,
+    passed: bool,
+}
 
-    fn from_bytes(bytes: Cow<[u8]>) -> Self {
-        Decode!(bytes.as_ref(), Self).unwrap()
-    }
+// Principal that satisfied a capsule's conditions through unlock_capsule
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct VerifiedUnlockKey {
+    capsule_id: u64,
+    principal: Principal,
 }
 
-impl BoundedStorable for TimeCapsule {
-    const MAX_SIZE: u32 = 1024 * 1024; // 1MB max size
-    const IS_FIXED_SIZE: bool = false;
+// Background pass over CAPSULE_STORAGE started by post_upgrade; it survives later upgrades and resumes
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct MigrationProgress {
+    rewrite_records: bool, // Rewrite every record in the current envelope
+    rebuild_indexes: bool, // Re-add every capsule to the unlock queue and secondary indexes
+    start_after: Option<u64>, // Last capsule ID processed
 }
 
-// Create a new time capsule
-#[ic_cdk::update]
-fn create_time_capsule(payload: CreateCapsulePayload) -> Result<TimeCapsule, String> {
-    let caller = ic_cdk::caller().to_string();
-    let current_time = time();
-    
-    if payload.unlock_date <= current_time {
-        return Err("Unlock date must be in the future".to_string());
-    }
+// Entry of the unlock queue, ordered by due time
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct ScheduleKey {
+    due_at: u64,
+    capsule_id: u64,
+}
 
-    let capsule_id = ID_COUNTER.with(|counter| {
-        let current_value = *counter.borrow().get();
-        counter.borrow_mut().set(current_value + 1)
-            .expect("Failed to increment counter");
-        current_value
-    });
+// Entry of the geo index: interleaved latitude/longitude bits, then capsule ID
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct GeoKey {
+    cell: u64,
+    capsule_id: u64,
+}
 
-    let capsule = TimeCapsule {
-        id: capsule_id,
-        creator: caller,
-        creation_date: current_time,
-        unlock_date: payload.unlock_date,
-        content: payload.content,
-        access_control: payload.access_control,
-        metadata: payload.metadata,
-        status: CapsuleStatus::Sealed,
-    };
+// Entry of the creator index
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct CreatorKey {
+    creator: Principal,
+    capsule_id: u64,
+}
 
-    CAPSULE_STORAGE.with(|storage| {
-        storage.borrow_mut().insert(capsule_id, capsule.clone());
-    });
+// Entry of the viewer index: a principal other than the creator that a capsule names
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct ViewerKey {
+    viewer: Principal,
+    capsule_id: u64,
+}
 
-    Ok(capsule)
+// Entry of the tag index, keyed by the normalized tag
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct TagKey {
+    tag: String,
+    capsule_id: u64,
 }
 
-// Retrieve a time capsule if conditions are met
-#[ic_cdk::query]
-fn get_capsule(capsule_id: u64) -> Result<TimeCapsule, String> {
-    let caller = ic_cdk::caller().to_string();
-    let current_time = time();
+// Entry of the unlock-date index, also the cursor of get_capsules_unlocking_between
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct UnlockDateKey {
+    unlock_date: u64,
+    capsule_id: u64,
+}
 
-    CAPSULE_STORAGE.with(|storage| {
-        if let Some(capsule) = storage.borrow().get(&capsule_id) {
-            // Check if capsule is unlockable
-            if current_time < capsule.unlock_date {
-                return Err("Capsule is still sealed".to_string());
-            }
-
-            // Check access control
-            match &capsule.access_control {
-                AccessControl::Public => Ok(capsule),
-                AccessControl::Private { allowed_viewers } => {
-                    if allowed_viewers.contains(&caller) || capsule.creator == caller {
-                        Ok(capsule)
-                    } else {
-                        Err("Access denied".to_string())
-                    }
-                }
-                AccessControl::Conditional { condition_type, condition_data } => {
-                    // Implement condition checking logic
-                    validate_condition(condition_type, condition_data, &caller)
-                        .map(|_| capsule)
-                }
-            }
-        } else {
-            Err("Capsule not found".to_string())
-        }
-    })
+// Entry of the search index, mapped to the term's weight in the capsule
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct SearchKey {
+    term: String,
+    capsule_id: u64,
 }
 
-// Function to validate conditional access
-fn validate_condition(condition_type: &str, condition_data: &str, caller: &str) -> Result<(), String> {
-    match condition_type {
-        "token_holder" => {
-            // Token holding verification
-            Ok(())
-        }
-        "geo_location" => {
-            // Location verification
-            Ok(())
-        }
-        "quiz" => {
-            // Quiz verification
-            Ok(())
-        }
-        _ => Err("Unknown condition type".to_string()),
-    }
+// Approval of a guardian for a capsule
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct GuardianApprovalKey {
+    capsule_id: u64,
+    guardian: Principal,
 }
 
-// Get all public capsules that are unlocked
-#[ic_cdk::query]
-fn get_public_capsules() -> Vec<TimeCapsule> {
-    let current_time = time();
-    
-    CAPSULE_STORAGE.with(|storage| {
-        storage.borrow()
-            .iter()
-            .filter(|(_, capsule)| {
-                matches!(capsule.access_control, AccessControl::Public) && 
-                current_time >= capsule.unlock_date
-            })
-            .map(|(_, capsule)| capsule)
-            .collect()
-    })
+// Blob uploaded in chunks, referenced by at most one capsule
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct BlobRecord {
+    owner: Principal,
+    size: u64,
+    sha256: Vec<u8>,
+    expires_at: Option<u64>, // None while a capsule references the blob
+    finalized: bool,
+    capsule_id: Option<u64>,
 }
 
-// Get capsules by location
-#[ic_cdk::query]
-fn get_capsules_by_location(latitude: f64, longitude: f64, radius_km: f64) -> Vec<TimeCapsule> {
-    CAPSULE_STORAGE.with(|storage| {
-        storage.borrow()
-            .iter()
-            .filter(|(_, capsule)| {
-                if let Some(location) = &capsule.metadata.location {
-                    calculate_distance(
-                        latitude, longitude,
-                        location.latitude, location.longitude
-                    ) <= radius_km
-                } else {
-                    false
-                }
-            })
-            .map(|(_, capsule)| capsule)
-            .collect()
-    })
+// Chunk of a blob, ordered by blob then index
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct BlobChunkKey {
+    blob_id: u64,
+    index: u32,
 }
 
-// Helper function to calculate distance between two points
-fn calculate_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
-    // Haversine formula implementation
-    const R: f64 = 6371.0; // Earth's radius in kilometers
-    
-    let lat1_rad = lat1.to_radians();
-    let lat2_rad = lat2.to_radians();
-    let delta_lat = (lat2 - lat1).to_radians();
-    let delta_lon = (lon2 - lon1).to_radians();
+// Blob of a principal that no capsule references, counted against the upload quota
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct PendingUploadKey {
+    owner: Principal,
+    blob_id: u64,
+}
 
-    let a = (delta_lat / 2.0).sin().powi(2) +
-        lat1_rad.cos() * lat2_rad.cos() * (delta_lon / 2.0).sin().powi(2);
-    let c = 2.0 * a.sqrt().asin();
+// Entry of the upload sweep queue, ordered by expiry time
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct UploadExpiryKey {
+    expires_at: u64,
+    blob_id: u64,
+}
 
-    R * c
+// Revision of a capsule, recorded at creation and on every edit
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CapsuleRevision {
+    revision: u32,
+    changed_at: u64,
+    changed_fields: Vec<String>, // Empty for the creation revision
+    content_digest: Vec<u8>,     // SHA-256 of the candid-encoded content
+    unlock_date: Option<u64>,    // Unlock date as of this revision
 }
 
-// Export Candid interface
-ic_cdk::export_candid!();
\ No newline at end of file
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct RevisionKey {
+    capsule_id: u64,
+    revision: u32,
+}
+
+// Raw chunk bytes, stored without candid framing
+#[derive(Clone)]
+struct BlobChunk(Vec<u8>);
+
+// Capsule layout from before typed principals (schema version 2), kept to decode existing storage
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum AccessControlV2 {
+    Public,
+    Private {
+        allowed_viewers: Vec<String>,
+    },
+    Conditional {
+        condition: UnlockCondition,
+    },
+    ConditionTree {
+        root: ConditionExpr,
+    },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TimeCapsuleV2 {
+    id: u64,
+    creator: String,
+    creation_date: u64,
+    unlock_date: u64,
+    content: CapsuleContent,
+    access_control: AccessControlV2,
+    metadata: CapsuleMetadata,
+    status: CapsuleStatus,
+    editable_until: Option<u64>,
+    unlock_date_locked: Option<bool>,
+    early_unlock_guardians: Option<GuardianQuorum>,
+    early_unlocked_at: Option<u64>,
+    dead_man_switch: Option<DeadManSwitch>,
+    last_checkin: Option<u64>,
+}
+
+// Capsule layout from before encrypted envelopes (schema version 1), kept to decode existing storage
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum CapsuleContentV1 {
+    Text(String),
+    EncryptedMessage {
+        content: Vec<u8>,
+        public_key: String,
+    },
+    MediaReference {
+        ipfs_hash: String,
+        media_type: String,
+    },
+    MultipartMessage {
+        parts: Vec<CapsuleContentV1>,
+        title: String,
+    },
+    Blob {
+        blob_id: u64,
+        media_type: String,
+        size: u64,
+        sha256: Vec<u8>,
+    },
+    Committed {
+        commitment: Vec<u8>,
+    },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TimeCapsuleV1 {
+    id: u64,
+    creator: String,
+    creation_date: u64,
+    unlock_date: u64,
+    content: CapsuleContentV1,
+    access_control: AccessControlV2,
+    metadata: CapsuleMetadata,
+    status: CapsuleStatus,
+    editable_until: Option<u64>,
+    unlock_date_locked: Option<bool>,
+    early_unlock_guardians: Option<GuardianQuorum>,
+    early_unlocked_at: Option<u64>,
+    dead_man_switch: Option<DeadManSwitch>,
+    last_checkin: Option<u64>,
+}
+
+// Capsule layout from before typed unlock conditions (schema version 0), kept to decode existing storage
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum LegacyAccessControl {
+    Public,
+    Private {
+        allowed_viewers: Vec<String>,
+    },
+    Conditional {
+        condition_type: String,
+        condition_data: String,
+    },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LegacyTimeCapsule {
+    id: u64,
+    creator: String,
+    creation_date: u64,
+    unlock_date: u64,
+    content: CapsuleContentV1,
+    access_control: LegacyAccessControl,
+    metadata: CapsuleMetadata,
+    status: CapsuleStatus,
+}
+
+// JSON condition data of legacy "token_holder" conditions
+#[derive(Deserialize)]
+struct LegacyTokenGate {
+    ledger_canister_id: String,
+    min_balance: u128,
+    subaccount: Option<Vec<u8>>,
+}
+
+// JSON condition data of legacy "quiz" conditions
+#[derive(Deserialize)]
+struct LegacyQuiz {
+    questions: Vec<LegacyQuizQuestion>,
+    max_attempts: u32,
+}
+
+#[derive(Deserialize)]
+struct LegacyQuizQuestion {
+    question: String,
+    salt: String,
+    answer_hash: String,
+}
+
+// Redacted view of a capsule, without content or access control
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CapsuleSummary {
+    id: u64,
+    creator: Principal,
+    title: String,
+    tags: Vec<String>,
+    location: Option<GeoLocation>,
+    unlock_date: u64,
+    status: CapsuleStatus,
+    content_kind: ContentKind,
+    content_size: u64, // Bytes stored for the content, blobs included; media references and commitments count as 0
+    access_type: AccessType,
+    hidden_fields: Vec<MetadataField>, // Sealed metadata blanked out of this summary
+}
+
+// Top-level variant of a capsule's content
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum ContentKind {
+    Text,
+    EncryptedMessage,
+    MediaReference,
+    MultipartMessage,
+    Blob,
+    Committed,
+}
+
+// Variant of a capsule's access control, without its viewers or conditions
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum AccessType {
+    Public,
+    Private,
+    Conditional,
+    ConditionTree,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CapsuleSummaryPage {
+    items: Vec<CapsuleSummary>,
+    next_cursor: Option<u64>,
+}
+
+// Capsule as returned by discovery endpoints: full only if the caller could open it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum CapsuleView {
+    Full(Box<TimeCapsule>),
+    Summary(CapsuleSummary),
+}
+
+// Cursor-based page request: up to `limit` items with IDs greater than `start_after`
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PageRequest {
+    start_after: Option<u64>,
+    limit: u32,
+}
+
+// Page of full capsules; `next_cursor` is the `start_after` of the next page, if there is one
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CapsulePage {
+    items: Vec<TimeCapsule>,
+    next_cursor: Option<u64>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CapsuleViewPage {
+    items: Vec<CapsuleView>,
+    next_cursor: Option<u64>,
+}
+
+// Page request ordered by unlock date, then ID
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct UnlockDatePageRequest {
+    start_after: Option<UnlockDateKey>,
+    limit: u32,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct UnlockDatePage {
+    items: Vec<CapsuleView>,
+    next_cursor: Option<UnlockDateKey>,
+}
+
+// Page request ordered by geo index cell, then ID
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LocationPageRequest {
+    start_after: Option<GeoKey>,
+    limit: u32,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LocationPage {
+    items: Vec<CapsuleView>,
+    next_cursor: Option<GeoKey>,
+}
+
+// Position in search results: candidates are ranked in batches by capsule ID, and
+// within a batch by score, then ID
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SearchCursor {
+    // Last capsule ID covered by earlier batches
+    batch_after: Option<u64>,
+    // Last hit returned from the current batch
+    last_hit: Option<SearchPosition>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SearchPosition {
+    score: u32,
+    capsule_id: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SearchPageRequest {
+    start_after: Option<SearchCursor>,
+    limit: u32,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SearchHit {
+    capsule: TimeCapsule,
+    score: u32,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SearchPage {
+    items: Vec<SearchHit>,
+    next_cursor: Option<SearchCursor>,
+}
+
+// Errors returned by the canister API
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum CapsuleError {
+    NotFound,
+    StillSealed { unlock_date: u64 },
+    AccessDenied,
+    ConditionFailed { reason: String },
+    InvalidPayload { field: String, reason: String },
+    InvalidOperation { reason: String },
+    IncorrectAnswers { attempts_remaining: u32 },
+    QuotaExceeded { limit: u64 },
+}
+
+// Payload for creating a new time capsule
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CreateCapsulePayload {
+    content: CapsuleContent,
+    unlock_date: u64,
+    access_control: AccessControl,
+    metadata: CapsuleMetadata,
+    edit_grace_period: Option<u64>, // Nanoseconds in which the capsule can still be edited or cancelled
+    unlock_date_locked: Option<bool>, // Forbid postponing the unlock date with extend_unlock_date
+    early_unlock_guardians: Option<GuardianQuorum>, // M-of-N guardians who can unlock before unlock_date
+    dead_man_switch: Option<DeadManSwitch>, // Release early unless the creator keeps checking in
+    reserved_id: Option<u64>, // ID from reserve_capsule_id, needed to encrypt to the capsule's vetKD key
+    sealed_metadata: Option<Vec<MetadataField>>, // Metadata to hide until unlock, e.g. the title of a surprise
+}
+
+// Fields to change during the grace period; unset fields keep their value
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct UpdateCapsulePayload {
+    content: Option<CapsuleContent>,
+    unlock_date: Option<u64>,
+    access_control: Option<AccessControl>,
+    metadata: Option<CapsuleMetadata>,
+    sealed_metadata: Option<Vec<MetadataField>>,
+}
+
+// Public description of an encrypted part of a capsule, without its ciphertext
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct EncryptionInfo {
+    algorithm: EncryptionAlgorithm,
+    key_fingerprint: Vec<u8>,
+    content_hash: Vec<u8>,
+    ciphertext_size: u64,
+}
+
+// Capsule ID handed out before creation, so content can be encrypted to it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct IdReservation {
+    owner: Principal,
+    reserved_at: u64,
+}
+
+// Reservation of a principal, counted against MAX_RESERVED_IDS
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+struct ReservationKey {
+    owner: Principal,
+    capsule_id: u64,
+}
+
+// Key derivations paid by the canister since `started_at`
+#[derive(Default)]
+struct DerivationWindow {
+    started_at: u64,
+    total: u32,
+    by_caller: BTreeMap<Principal, u32>,
+}
+
+// Management canister vetKD API
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum VetKdCurve {
+    #[serde(rename = "bls12_381_g2")]
+    Bls12381G2,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct VetKdKeyId {
+    curve: VetKdCurve,
+    name: String,
+}
+
+#[cfg(not(feature = "mock-vetkd"))]
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct VetKdPublicKeyArgs {
+    canister_id: Option<Principal>,
+    context: Vec<u8>,
+    key_id: VetKdKeyId,
+}
+
+#[cfg(not(feature = "mock-vetkd"))]
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct VetKdPublicKeyResult {
+    public_key: Vec<u8>,
+}
+
+#[cfg(not(feature = "mock-vetkd"))]
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct VetKdDeriveKeyArgs {
+    input: Vec<u8>,
+    context: Vec<u8>,
+    transport_public_key: Vec<u8>,
+    key_id: VetKdKeyId,
+}
+
+#[cfg(not(feature = "mock-vetkd"))]
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct VetKdDeriveKeyResult {
+    encrypted_key: Vec<u8>,
+}
+
+// Payload for starting a chunked upload
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct BeginUploadPayload {
+    size: u64,
+    sha256: Vec<u8>,
+}
+
+// Storage implementation
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    static CAPSULE_STORAGE: RefCell<StableBTreeMap<u64, TimeCapsule, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0)))
+        )
+    );
+
+    static ID_COUNTER: RefCell<Cell<u64, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))), 0)
+            .expect("Cannot create counter")
+    );
+
+    static QUIZ_PROGRESS: RefCell<StableBTreeMap<QuizAttemptKey, QuizProgress, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))
+        )
+    );
+
+    static GUARDIAN_APPROVALS: RefCell<StableBTreeMap<GuardianApprovalKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3)))
+        )
+    );
+
+    // Sealed capsules by unlock date, so the scheduler never scans CAPSULE_STORAGE
+    static UNLOCK_QUEUE: RefCell<StableBTreeMap<ScheduleKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+        )
+    );
+
+    // Located capsules by geohash cell, used to prune location queries
+    static GEO_INDEX: RefCell<StableBTreeMap<GeoKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+        )
+    );
+
+    // Capsule schema version of the stored data, compared against CAPSULE_SCHEMA_VERSION on upgrade
+    static STORAGE_VERSION: RefCell<Cell<u32, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))), 0)
+            .expect("Cannot create storage version")
+    );
+
+    // Uploaded blobs, with their chunks kept apart so capsules stay small
+    static BLOBS: RefCell<StableBTreeMap<u64, BlobRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7)))
+        )
+    );
+
+    static BLOB_CHUNKS: RefCell<StableBTreeMap<BlobChunkKey, BlobChunk, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+        )
+    );
+
+    static BLOB_ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))), 0)
+            .expect("Cannot create blob counter")
+    );
+
+    // Revisions of every capsule, kept for as long as the capsule exists
+    static CAPSULE_HISTORY: RefCell<StableBTreeMap<RevisionKey, CapsuleRevision, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))
+        )
+    );
+
+    // Approvals of early-unlock guardians, kept apart from Guardians condition approvals
+    static EARLY_UNLOCK_APPROVALS: RefCell<StableBTreeMap<GuardianApprovalKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+        )
+    );
+
+    // Capsule IDs reserved by reserve_capsule_id and not used yet
+    static RESERVED_IDS: RefCell<StableBTreeMap<u64, IdReservation, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+        )
+    );
+
+    // Secondary indexes over CAPSULE_STORAGE, updated with every write that touches their fields
+    static CREATOR_INDEX: RefCell<StableBTreeMap<CreatorKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13)))
+        )
+    );
+
+    static TAG_INDEX: RefCell<StableBTreeMap<TagKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14)))
+        )
+    );
+
+    static UNLOCK_DATE_INDEX: RefCell<StableBTreeMap<UnlockDateKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+        )
+    );
+
+    // Index layout of the stored indexes, compared against INDEX_SCHEMA_VERSION on upgrade
+    static INDEX_VERSION: RefCell<Cell<u32, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))), 0)
+            .expect("Cannot create index version")
+    );
+
+    static MIGRATION_PROGRESS: RefCell<Cell<MigrationProgress, Memory>> = RefCell::new(
+        Cell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))), MigrationProgress::default())
+            .expect("Cannot create migration progress")
+    );
+
+    // Words of unlocked public capsules; sealed content never enters it
+    static SEARCH_INDEX: RefCell<StableBTreeMap<SearchKey, u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+        )
+    );
+
+    // Time until which a principal that satisfied a capsule's conditions can read it through queries
+    static VERIFIED_UNLOCKS: RefCell<StableBTreeMap<VerifiedUnlockKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+        )
+    );
+
+    static PENDING_UPLOADS: RefCell<StableBTreeMap<PendingUploadKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+        )
+    );
+
+    static UPLOAD_EXPIRY: RefCell<StableBTreeMap<UploadExpiryKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+        )
+    );
+
+    static RESERVATIONS_BY_OWNER: RefCell<StableBTreeMap<ReservationKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+        )
+    );
+
+    static VIEWER_INDEX: RefCell<StableBTreeMap<ViewerKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+        )
+    );
+
+    // The vetKD public key never changes, so it is fetched once per canister version
+    static VETKD_PUBLIC_KEY: RefCell<Option<Vec<u8>>> = const { RefCell::new(None) };
+
+    // Only bounds spending, so it may reset on upgrade
+    static KEY_DERIVATIONS: RefCell<DerivationWindow> = RefCell::new(DerivationWindow::default());
+
+    // Timers don't survive upgrades, so the pending timer lives on the heap
+    static UNLOCK_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+}
+
+// Implementation for TimeCapsule
+impl Storable for TimeCapsule {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = CAPSULE_ENVELOPE_MAGIC.to_vec();
+        bytes.extend_from_slice(&CAPSULE_SCHEMA_VERSION.to_be_bytes());
+        bytes.extend(Encode!(self).unwrap());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        decode_stored_capsule(&bytes)
+            .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to decode capsule: {}", e)))
+    }
+}
+
+// Decode a stored capsule record of any known schema version into the current layout
+fn decode_stored_capsule(bytes: &[u8]) -> Result<TimeCapsule, String> {
+    match bytes.strip_prefix(CAPSULE_ENVELOPE_MAGIC) {
+        Some([v0, v1, v2, v3, payload @ ..]) => {
+            migrate_capsule(u32::from_be_bytes([*v0, *v1, *v2, *v3]), payload)
+        }
+        Some(_) => Err("Truncated capsule envelope".to_string()),
+        // The envelope arrived with schema 1, so bare records are always the baseline layout
+        None => migrate_capsule(0, bytes),
+    }
+}
+
+// Decode a payload of the given schema version, migrating it to the current layout
+fn migrate_capsule(version: u32, payload: &[u8]) -> Result<TimeCapsule, String> {
+    match version {
+        0 => Decode!(payload, LegacyTimeCapsule)
+            .map(|legacy| TimeCapsuleV2::from(legacy).into())
+            .map_err(|e| e.to_string()),
+        1 => Decode!(payload, TimeCapsuleV1)
+            .map(|v1| TimeCapsuleV2::from(v1).into())
+            .map_err(|e| e.to_string()),
+        2 => Decode!(payload, TimeCapsuleV2)
+            .map(TimeCapsule::from)
+            .map_err(|e| e.to_string()),
+        3 => Decode!(payload, TimeCapsule).map_err(|e| e.to_string()),
+        _ => Err(format!("Unknown capsule schema version {}", version)),
+    }
+}
+
+impl BoundedStorable for TimeCapsule {
+    const MAX_SIZE: u32 = 1024 * 1024; // 1MB max size
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for MigrationProgress {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for BlobRecord {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for BlobRecord {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for BlobChunkKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for BlobChunkKey {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for PendingUploadKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PendingUploadKey {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for UploadExpiryKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for UploadExpiryKey {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for BlobChunk {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        BlobChunk(bytes.into_owned())
+    }
+}
+
+impl BoundedStorable for BlobChunk {
+    const MAX_SIZE: u32 = BLOB_CHUNK_SIZE as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for CapsuleRevision {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CapsuleRevision {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for RevisionKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RevisionKey {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for IdReservation {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for IdReservation {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for ReservationKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ReservationKey {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for QuizAttemptKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for QuizAttemptKey {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for QuizProgress {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for QuizProgress {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for VerifiedUnlockKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for VerifiedUnlockKey {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for GuardianApprovalKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for GuardianApprovalKey {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for ScheduleKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ScheduleKey {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for GeoKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for GeoKey {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for CreatorKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CreatorKey {
+    const MAX_SIZE: u32 = 96;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for ViewerKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ViewerKey {
+    const MAX_SIZE: u32 = 96;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for TagKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TagKey {
+    const MAX_SIZE: u32 = MAX_TAG_LENGTH as u32 + 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for UnlockDateKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for UnlockDateKey {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl Storable for SearchKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SearchKey {
+    const MAX_SIZE: u32 = MAX_TERM_LENGTH as u32 + 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+impl From<TimeCapsuleV1> for TimeCapsuleV2 {
+    fn from(v1: TimeCapsuleV1) -> Self {
+        TimeCapsuleV2 {
+            id: v1.id,
+            creator: v1.creator,
+            creation_date: v1.creation_date,
+            unlock_date: v1.unlock_date,
+            content: v1.content.into(),
+            access_control: v1.access_control,
+            metadata: v1.metadata,
+            status: v1.status,
+            editable_until: v1.editable_until,
+            unlock_date_locked: v1.unlock_date_locked,
+            early_unlock_guardians: v1.early_unlock_guardians,
+            early_unlocked_at: v1.early_unlocked_at,
+            dead_man_switch: v1.dead_man_switch,
+            last_checkin: v1.last_checkin,
+        }
+    }
+}
+
+impl From<CapsuleContentV1> for CapsuleContent {
+    fn from(v1: CapsuleContentV1) -> Self {
+        match v1 {
+            CapsuleContentV1::Text(text) => CapsuleContent::Text(text),
+            // The old fields carry no algorithm or nonce; keep the bytes so nothing is lost
+            CapsuleContentV1::EncryptedMessage { content, public_key } => {
+                let recipient_public_key = public_key.into_bytes();
+                CapsuleContent::EncryptedMessage(EncryptedEnvelope {
+                    algorithm: EncryptionAlgorithm::Unspecified,
+                    key_fingerprint: Sha256::digest(&recipient_public_key).to_vec(),
+                    recipient_public_key,
+                    nonce: Vec::new(),
+                    content_hash: Sha256::digest(&content).to_vec(),
+                    ciphertext: content,
+                    wrapped_keys: None,
+                })
+            }
+            CapsuleContentV1::MediaReference { ipfs_hash, media_type } => {
+                CapsuleContent::MediaReference { ipfs_hash, media_type }
+            }
+            CapsuleContentV1::MultipartMessage { parts, title } => CapsuleContent::MultipartMessage {
+                parts: parts.into_iter().map(CapsuleContent::from).collect(),
+                title,
+            },
+            CapsuleContentV1::Blob {
+                blob_id,
+                media_type,
+                size,
+                sha256,
+            } => CapsuleContent::Blob {
+                blob_id,
+                media_type,
+                size,
+                sha256,
+            },
+            CapsuleContentV1::Committed { commitment } => CapsuleContent::Committed { commitment },
+        }
+    }
+}
+
+impl From<LegacyTimeCapsule> for TimeCapsuleV2 {
+    fn from(legacy: LegacyTimeCapsule) -> Self {
+        let access_control = match legacy.access_control {
+            LegacyAccessControl::Public => AccessControlV2::Public,
+            LegacyAccessControl::Private { allowed_viewers } => AccessControlV2::Private { allowed_viewers },
+            LegacyAccessControl::Conditional { condition_type, condition_data } => {
+                match migrate_legacy_condition(&condition_type, &condition_data) {
+                    Some(condition) => AccessControlV2::Conditional { condition },
+                    // Conditions that can't be expressed anymore fall back to creator-only access
+                    None => AccessControlV2::Private { allowed_viewers: Vec::new() },
+                }
+            }
+        };
+
+        TimeCapsuleV2 {
+            id: legacy.id,
+            creator: legacy.creator,
+            creation_date: legacy.creation_date,
+            unlock_date: legacy.unlock_date,
+            content: legacy.content.into(),
+            access_control,
+            metadata: legacy.metadata,
+            status: legacy.status,
+            editable_until: None,
+            unlock_date_locked: None,
+            early_unlock_guardians: None,
+            early_unlocked_at: None,
+            dead_man_switch: None,
+            last_checkin: None,
+        }
+    }
+}
+
+impl From<TimeCapsuleV2> for TimeCapsule {
+    fn from(v2: TimeCapsuleV2) -> Self {
+        let mut access_control = match v2.access_control {
+            AccessControlV2::Public => AccessControl::Public,
+            // Viewers that never were valid principals could not have called in, so dropping them changes nothing
+            AccessControlV2::Private { allowed_viewers } => AccessControl::Private {
+                allowed_viewers: allowed_viewers
+                    .iter()
+                    .filter_map(|viewer| Principal::from_text(viewer).ok())
+                    .collect(),
+            },
+            AccessControlV2::Conditional { condition } => AccessControl::Conditional { condition },
+            AccessControlV2::ConditionTree { root } => AccessControl::ConditionTree { root },
+        };
+        normalize_access_control(&mut access_control);
+
+        TimeCapsule {
+            id: v2.id,
+            // Creators were always recorded from the caller; an unparsable one leaves the capsule without an owner
+            creator: Principal::from_text(&v2.creator).unwrap_or_else(|_| Principal::management_canister()),
+            creation_date: v2.creation_date,
+            unlock_date: v2.unlock_date,
+            content: v2.content,
+            access_control,
+            metadata: v2.metadata,
+            status: v2.status,
+            editable_until: v2.editable_until,
+            unlock_date_locked: v2.unlock_date_locked,
+            early_unlock_guardians: v2.early_unlock_guardians,
+            early_unlocked_at: v2.early_unlocked_at,
+            dead_man_switch: v2.dead_man_switch,
+            last_checkin: v2.last_checkin,
+            sealed_metadata: None,
+        }
+    }
+}
+
+// Convert a stringly-typed legacy condition into its typed form
+fn migrate_legacy_condition(condition_type: &str, condition_data: &str) -> Option<UnlockCondition> {
+    match condition_type {
+        "token_holder" => {
+            let gate: LegacyTokenGate = serde_json::from_str(condition_data).ok()?;
+            Some(UnlockCondition::TokenHolder(TokenGate {
+                ledger_canister_id: Principal::from_text(&gate.ledger_canister_id).ok()?,
+                min_balance: Nat::from(gate.min_balance),
+                subaccount: gate.subaccount,
+            }))
+        }
+        "quiz" => {
+            let quiz: LegacyQuiz = serde_json::from_str(condition_data).ok()?;
+            let questions = quiz
+                .questions
+                .into_iter()
+                .map(|q| {
+                    Some(QuizQuestion {
+                        question: q.question,
+                        salt: hex::decode(q.salt).ok()?,
+                        answer_hash: hex::decode(q.answer_hash).ok()?,
+                    })
+                })
+                .collect::<Option<Vec<_>>>()?;
+            Some(UnlockCondition::Quiz(QuizChallenge {
+                questions,
+                max_attempts: quiz.max_attempts,
+            }))
+        }
+        _ => None,
+    }
+}
+
+#[ic_cdk::init]
+fn init() {
+    set_storage_version(CAPSULE_SCHEMA_VERSION);
+    set_index_version(INDEX_SCHEMA_VERSION);
+    arm_unlock_timer();
+    ic_cdk_timers::set_timer_interval(SWEEP_INTERVAL, sweep_expired);
+}
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    // All state lives in stable structures; record the layout this build wrote
+    // so the next build knows which migrations to run
+    set_storage_version(CAPSULE_SCHEMA_VERSION);
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    // Builds before versioning never wrote these cells, so they read as 0 and
+    // every record is rewritten and every index rebuilt once
+    let rewrite_records = STORAGE_VERSION.with(|version| *version.borrow().get()) < CAPSULE_SCHEMA_VERSION;
+    let rebuild_indexes = INDEX_VERSION.with(|version| *version.borrow().get()) < INDEX_SCHEMA_VERSION;
+
+    if rewrite_records || rebuild_indexes {
+        // Records decode from any known version on read, so the pass can run in the
+        // background; a pass left over from an earlier upgrade restarts with both jobs
+        MIGRATION_PROGRESS.with(|progress| {
+            let mut progress = progress.borrow_mut();
+            let pending = progress.get().clone();
+            progress
+                .set(MigrationProgress {
+                    rewrite_records: rewrite_records || pending.rewrite_records,
+                    rebuild_indexes: rebuild_indexes || pending.rebuild_indexes,
+                    start_after: None,
+                })
+                .expect("Cannot write migration progress");
+        });
+    }
+    set_storage_version(CAPSULE_SCHEMA_VERSION);
+    set_index_version(INDEX_SCHEMA_VERSION);
+
+    arm_unlock_timer();
+    ic_cdk_timers::set_timer_interval(SWEEP_INTERVAL, sweep_expired);
+    schedule_migration_batch();
+}
+
+// Run the next migration batch in its own message, if a pass is pending
+fn schedule_migration_batch() {
+    let progress = MIGRATION_PROGRESS.with(|progress| progress.borrow().get().clone());
+    if progress.rewrite_records || progress.rebuild_indexes {
+        ic_cdk_timers::set_timer(Duration::ZERO, run_migration_batch);
+    }
+}
+
+// Migrate one batch of capsules, then schedule the next. A record that fails to decode
+// traps the batch and stops the pass; the next upgrade resumes it from the last batch
+fn run_migration_batch() {
+    let current_time = time();
+    let mut progress = MIGRATION_PROGRESS.with(|progress| progress.borrow().get().clone());
+
+    let batch: Vec<(u64, TimeCapsule)> = CAPSULE_STORAGE.with(|storage| {
+        let lower = progress.start_after.map_or(Bound::Unbounded, Bound::Excluded);
+        storage
+            .borrow()
+            .range((lower, Bound::Unbounded))
+            .take(MIGRATION_BATCH_SIZE)
+            .collect()
+    });
+
+    for (id, capsule) in &batch {
+        if progress.rewrite_records {
+            CAPSULE_STORAGE.with(|storage| storage.borrow_mut().insert(*id, capsule.clone()));
+        }
+        // Queue and index entries are plain keys, so inserting an existing one is harmless
+        if progress.rebuild_indexes {
+            if matches!(capsule.status, CapsuleStatus::Sealed | CapsuleStatus::UnlockPending) {
+                UNLOCK_QUEUE.with(|queue| {
+                    let mut queue = queue.borrow_mut();
+                    for due_at in scheduled_times(capsule) {
+                        queue.insert(ScheduleKey { due_at, capsule_id: *id }, ());
+                    }
+                });
+            }
+            index_capsule(capsule, current_time);
+        }
+    }
+
+    if progress.rebuild_indexes {
+        arm_unlock_timer();
+    }
+    progress = match batch.last() {
+        Some(&(last_id, _)) if batch.len() == MIGRATION_BATCH_SIZE => MigrationProgress {
+            start_after: Some(last_id),
+            ..progress
+        },
+        _ => MigrationProgress::default(),
+    };
+    MIGRATION_PROGRESS.with(|cell| cell.borrow_mut().set(progress).expect("Cannot write migration progress"));
+
+    schedule_migration_batch();
+}
+
+fn set_index_version(index_version: u32) {
+    INDEX_VERSION.with(|version| {
+        version
+            .borrow_mut()
+            .set(index_version)
+            .expect("Cannot write index version")
+    });
+}
+
+fn set_storage_version(schema_version: u32) {
+    STORAGE_VERSION.with(|version| {
+        version
+            .borrow_mut()
+            .set(schema_version)
+            .expect("Cannot write storage version")
+    });
+}
+
+// Create a new time capsule
+#[ic_cdk::update]
+fn create_time_capsule(payload: CreateCapsulePayload) -> Result<TimeCapsule, CapsuleError> {
+    let caller = ic_cdk::caller();
+    let current_time = time();
+
+    validate_capsule_fields(&payload.content, payload.unlock_date, &payload.access_control, &payload.metadata, current_time)?;
+
+    if let Some(quorum) = &payload.early_unlock_guardians {
+        validate_guardian_quorum(quorum).map_err(|reason| CapsuleError::InvalidPayload {
+            field: "early_unlock_guardians".to_string(),
+            reason,
+        })?;
+    }
+
+    if let Some(switch) = &payload.dead_man_switch {
+        if switch.checkin_interval < MIN_CHECKIN_INTERVAL {
+            return Err(CapsuleError::InvalidPayload {
+                field: "dead_man_switch.checkin_interval".to_string(),
+                reason: format!("Check-in interval must be at least {} nanoseconds", MIN_CHECKIN_INTERVAL),
+            });
+        }
+    }
+
+    let editable_until = match payload.edit_grace_period {
+        Some(period) if period > MAX_EDIT_GRACE_PERIOD => {
+            return Err(CapsuleError::InvalidPayload {
+                field: "edit_grace_period".to_string(),
+                reason: format!("Grace period can be at most {} nanoseconds", MAX_EDIT_GRACE_PERIOD),
+            });
+        }
+        Some(period) if current_time + period >= payload.unlock_date => {
+            return Err(CapsuleError::InvalidPayload {
+                field: "edit_grace_period".to_string(),
+                reason: "Grace period must end before the unlock date".to_string(),
+            });
+        }
+        Some(period) => Some(current_time + period),
+        None => None,
+    };
+
+    let blob_ids = validate_blob_refs(&payload.content, &caller, None, current_time).map_err(|reason| CapsuleError::InvalidPayload {
+        field: "content".to_string(),
+        reason,
+    })?;
+
+    // vetKD content is encrypted to the capsule's ID, which only a reservation makes known in advance
+    if payload.reserved_id.is_none() && uses_vetkd(&payload.content) {
+        return Err(CapsuleError::InvalidPayload {
+            field: "reserved_id".to_string(),
+            reason: "Content encrypted to the vetKD key needs a reserved capsule ID".to_string(),
+        });
+    }
+
+    let capsule_id = match payload.reserved_id {
+        Some(reserved_id) => {
+            let reservation = RESERVED_IDS
+                .with(|reserved| reserved.borrow().get(&reserved_id))
+                .filter(|reservation| reservation.owner == caller)
+                .ok_or_else(|| CapsuleError::InvalidPayload {
+                    field: "reserved_id".to_string(),
+                    reason: "ID is not reserved by the caller".to_string(),
+                })?;
+            if reservation_expired(&reservation, current_time) {
+                return Err(CapsuleError::InvalidPayload {
+                    field: "reserved_id".to_string(),
+                    reason: "Reservation has expired".to_string(),
+                });
+            }
+            reserved_id
+        }
+        None => ID_COUNTER.with(|counter| *counter.borrow().get()),
+    };
+
+    let mut access_control = payload.access_control;
+    normalize_access_control(&mut access_control);
+
+    let capsule = TimeCapsule {
+        id: capsule_id,
+        creator: caller,
+        creation_date: current_time,
+        unlock_date: payload.unlock_date,
+        content: payload.content,
+        access_control,
+        metadata: payload.metadata,
+        status: CapsuleStatus::Sealed,
+        editable_until,
+        unlock_date_locked: payload.unlock_date_locked,
+        early_unlock_guardians: payload.early_unlock_guardians,
+        early_unlocked_at: None,
+        last_checkin: payload.dead_man_switch.as_ref().map(|_| current_time),
+        sealed_metadata: payload.sealed_metadata,
+        dead_man_switch: payload.dead_man_switch,
+    };
+    check_capsule_size(&capsule)?;
+
+    if payload.reserved_id.is_some() {
+        release_reservation(capsule_id, caller);
+    } else {
+        ID_COUNTER.with(|counter| {
+            counter.borrow_mut().set(capsule_id + 1)
+                .expect("Failed to increment counter");
+        });
+    }
+
+    CAPSULE_STORAGE.with(|storage| {
+        storage.borrow_mut().insert(capsule_id, capsule.clone());
+    });
+
+    schedule_capsule(&capsule);
+    arm_unlock_timer();
+    index_capsule(&capsule, current_time);
+    attach_blobs(capsule_id, &blob_ids);
+    record_revision(&capsule, Vec::new(), current_time);
+
+    Ok(capsule)
+}
+
+// Reserve the ID of a capsule the caller will create, e.g. to encrypt content to its vetKD key first
+#[ic_cdk::update]
+fn reserve_capsule_id() -> Result<u64, CapsuleError> {
+    let caller = ic_cdk::caller();
+    let current_time = time();
+
+    // The quota is per principal, so anonymous callers would all share one
+    if caller == Principal::anonymous() {
+        return Err(CapsuleError::AccessDenied);
+    }
+    let outstanding = RESERVATIONS_BY_OWNER.with(|by_owner| {
+        by_owner
+            .borrow()
+            .range(ReservationKey { owner: caller, capsule_id: 0 }..)
+            .take_while(|(key, _)| key.owner == caller)
+            .filter(|(key, _)| {
+                RESERVED_IDS
+                    .with(|reserved| reserved.borrow().get(&key.capsule_id))
+                    .is_some_and(|reservation| !reservation_expired(&reservation, current_time))
+            })
+            .count()
+    });
+    if outstanding >= MAX_RESERVED_IDS {
+        return Err(CapsuleError::QuotaExceeded {
+            limit: MAX_RESERVED_IDS as u64,
+        });
+    }
+
+    let capsule_id = ID_COUNTER.with(|counter| {
+        let current_value = *counter.borrow().get();
+        counter.borrow_mut().set(current_value + 1)
+            .expect("Failed to increment counter");
+        current_value
+    });
+
+    let reservation = IdReservation {
+        owner: caller,
+        reserved_at: current_time,
+    };
+    RESERVED_IDS.with(|reserved| reserved.borrow_mut().insert(capsule_id, reservation));
+    RESERVATIONS_BY_OWNER.with(|by_owner| by_owner.borrow_mut().insert(ReservationKey { owner: caller, capsule_id }, ()));
+
+    Ok(capsule_id)
+}
+
+fn reservation_expired(reservation: &IdReservation, current_time: u64) -> bool {
+    current_time >= reservation.reserved_at.saturating_add(RESERVATION_TTL)
+}
+
+fn release_reservation(capsule_id: u64, owner: Principal) {
+    RESERVED_IDS.with(|reserved| reserved.borrow_mut().remove(&capsule_id));
+    RESERVATIONS_BY_OWNER.with(|by_owner| by_owner.borrow_mut().remove(&ReservationKey { owner, capsule_id }));
+}
+
+// Drop reservations older than RESERVATION_TTL; their IDs are never handed out again.
+// IDs are reserved in order, so the oldest reservations come first
+fn sweep_expired_reservations() {
+    let current_time = time();
+    let expired: Vec<(u64, IdReservation)> = RESERVED_IDS.with(|reserved| {
+        reserved
+            .borrow()
+            .iter()
+            .take_while(|(_, reservation)| reservation_expired(reservation, current_time))
+            .take(SWEEP_BATCH_SIZE)
+            .collect()
+    });
+
+    for (capsule_id, reservation) in &expired {
+        release_reservation(*capsule_id, reservation.owner);
+    }
+
+    if expired.len() == SWEEP_BATCH_SIZE {
+        ic_cdk_timers::set_timer(Duration::ZERO, sweep_expired_reservations);
+    }
+}
+
+// Edit a capsule during its grace period
+#[ic_cdk::update]
+fn update_capsule(capsule_id: u64, payload: UpdateCapsulePayload) -> Result<TimeCapsule, CapsuleError> {
+    let capsule = edit_capsule(capsule_id, payload, ic_cdk::caller(), time())?;
+    arm_unlock_timer();
+    Ok(capsule)
+}
+
+fn edit_capsule(capsule_id: u64, payload: UpdateCapsulePayload, caller: Principal, current_time: u64) -> Result<TimeCapsule, CapsuleError> {
+    let previous = editable_capsule(capsule_id, &caller, current_time)?;
+    let mut capsule = previous.clone();
+
+    let mut changed_fields = Vec::new();
+    if let Some(content) = payload.content {
+        capsule.content = content;
+        changed_fields.push("content".to_string());
+    }
+    if let Some(unlock_date) = payload.unlock_date {
+        capsule.unlock_date = unlock_date;
+        changed_fields.push("unlock_date".to_string());
+    }
+    if let Some(mut access_control) = payload.access_control {
+        normalize_access_control(&mut access_control);
+        capsule.access_control = access_control;
+        changed_fields.push("access_control".to_string());
+    }
+    if let Some(metadata) = payload.metadata {
+        capsule.metadata = metadata;
+        changed_fields.push("metadata".to_string());
+    }
+    if let Some(sealed_metadata) = payload.sealed_metadata {
+        capsule.sealed_metadata = Some(sealed_metadata);
+        changed_fields.push("sealed_metadata".to_string());
+    }
+    if changed_fields.is_empty() {
+        return Err(CapsuleError::InvalidPayload {
+            field: "payload".to_string(),
+            reason: "No fields to update".to_string(),
+        });
+    }
+
+    validate_capsule_fields(&capsule.content, capsule.unlock_date, &capsule.access_control, &capsule.metadata, current_time)?;
+    if capsule.unlock_date != previous.unlock_date && uses_vetkd(&previous.content) && !content_changed(&changed_fields) {
+        return Err(CapsuleError::InvalidPayload {
+            field: "unlock_date".to_string(),
+            reason: "Unlock date is bound into the vetKD key; re-encrypt the content to change it".to_string(),
+        });
+    }
+    if capsule.editable_until.is_some_and(|until| until >= capsule.unlock_date) {
+        return Err(CapsuleError::InvalidPayload {
+            field: "unlock_date".to_string(),
+            reason: "Unlock date must be after the grace period".to_string(),
+        });
+    }
+    let blob_ids = validate_blob_refs(&capsule.content, &caller, Some(capsule_id), current_time).map_err(|reason| {
+        CapsuleError::InvalidPayload {
+            field: "content".to_string(),
+            reason,
+        }
+    })?;
+    check_capsule_size(&capsule)?;
+
+    unschedule_capsule(&previous);
+    unindex_capsule(&previous);
+    detach_blobs(&previous.content, current_time);
+    // Quiz passes, guardian approvals and verified unlocks were given for the old conditions
+    if changed_fields.iter().any(|field| field == "access_control") {
+        clear_condition_progress(capsule_id);
+    }
+
+    CAPSULE_STORAGE.with(|storage| {
+        storage.borrow_mut().insert(capsule_id, capsule.clone());
+    });
+
+    schedule_capsule(&capsule);
+    index_capsule(&capsule, current_time);
+    attach_blobs(capsule_id, &blob_ids);
+    record_revision(&capsule, changed_fields, current_time);
+
+    Ok(capsule)
+}
+
+// Delete a capsule during its grace period, along with its blobs and history
+#[ic_cdk::update]
+fn cancel_capsule(capsule_id: u64) -> Result<(), CapsuleError> {
+    delete_capsule(capsule_id, ic_cdk::caller(), time())?;
+    arm_unlock_timer();
+    Ok(())
+}
+
+fn delete_capsule(capsule_id: u64, caller: Principal, current_time: u64) -> Result<(), CapsuleError> {
+    let capsule = editable_capsule(capsule_id, &caller, current_time)?;
+
+    CAPSULE_STORAGE.with(|storage| storage.borrow_mut().remove(&capsule_id));
+    unschedule_capsule(&capsule);
+    unindex_capsule(&capsule);
+    clear_condition_progress(capsule_id);
+    EARLY_UNLOCK_APPROVALS.with(|approvals| clear_guardian_approvals(&mut approvals.borrow_mut(), capsule_id));
+
+    let mut refs = Vec::new();
+    collect_blob_refs(&capsule.content, &mut refs);
+    for (blob_id, size, _) in refs {
+        remove_blob(blob_id, size);
+    }
+
+    CAPSULE_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        let keys: Vec<RevisionKey> = history
+            .range(RevisionKey { capsule_id, revision: 0 }..)
+            .take_while(|(key, _)| key.capsule_id == capsule_id)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            history.remove(&key);
+        }
+    });
+
+    Ok(())
+}
+
+// Publish the content of a committed capsule once its time lock has passed
+#[ic_cdk::update]
+fn reveal_capsule(capsule_id: u64, content: CapsuleContent, salt: Vec<u8>) -> Result<TimeCapsule, CapsuleError> {
+    let current_time = time();
+
+    let mut capsule = CAPSULE_STORAGE
+        .with(|storage| storage.borrow().get(&capsule_id))
+        .ok_or(CapsuleError::NotFound)?;
+    check_reveal(&capsule, &content, &salt, current_time)?;
+
+    let blob_ids = validate_blob_refs(&content, &capsule.creator, Some(capsule_id), current_time).map_err(|reason| {
+        CapsuleError::InvalidPayload {
+            field: "content".to_string(),
+            reason,
+        }
+    })?;
+
+    let committed = std::mem::replace(&mut capsule.content, content);
+    check_capsule_size(&capsule)?;
+    unindex_capsule_text(&TimeCapsule {
+        content: committed,
+        ..capsule.clone()
+    });
+    CAPSULE_STORAGE.with(|storage| {
+        storage.borrow_mut().insert(capsule_id, capsule.clone());
+    });
+    index_capsule_text(&capsule);
+    attach_blobs(capsule_id, &blob_ids);
+    record_revision(&capsule, vec!["content".to_string()], current_time);
+
+    Ok(visible_to(capsule, &ic_cdk::caller()))
+}
+
+// Check that a committed capsule's time lock has passed and the content and salt match its commitment
+fn check_reveal(capsule: &TimeCapsule, content: &CapsuleContent, salt: &[u8], current_time: u64) -> Result<(), CapsuleError> {
+    let CapsuleContent::Committed { commitment } = &capsule.content else {
+        return Err(CapsuleError::InvalidOperation {
+            reason: "Capsule content is not committed".to_string(),
+        });
+    };
+    if !time_lock_passed(capsule, current_time) {
+        return Err(CapsuleError::StillSealed {
+            unlock_date: capsule.unlock_date,
+        });
+    }
+    if contains_commitment(content) {
+        return Err(CapsuleError::InvalidPayload {
+            field: "content".to_string(),
+            reason: "Revealed content cannot contain a commitment".to_string(),
+        });
+    }
+    validate_encrypted_content(content).map_err(|reason| CapsuleError::InvalidPayload {
+        field: "content".to_string(),
+        reason,
+    })?;
+    if commitment_hash(content, salt) != *commitment {
+        return Err(CapsuleError::InvalidPayload {
+            field: "content".to_string(),
+            reason: "Content and salt do not match the commitment".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Postpone a sealed capsule's unlock date; dates can only move forward
+#[ic_cdk::update]
+fn extend_unlock_date(capsule_id: u64, new_date: u64) -> Result<TimeCapsule, CapsuleError> {
+    let capsule = postpone_unlock_date(capsule_id, new_date, ic_cdk::caller(), time())?;
+    arm_unlock_timer();
+    Ok(capsule)
+}
+
+fn postpone_unlock_date(capsule_id: u64, new_date: u64, caller: Principal, current_time: u64) -> Result<TimeCapsule, CapsuleError> {
+    let mut capsule = CAPSULE_STORAGE
+        .with(|storage| storage.borrow().get(&capsule_id))
+        .ok_or(CapsuleError::NotFound)?;
+    if capsule.creator != caller {
+        return Err(CapsuleError::AccessDenied);
+    }
+    if capsule.unlock_date_locked == Some(true) {
+        return Err(CapsuleError::InvalidOperation {
+            reason: "Unlock date was locked at creation".to_string(),
+        });
+    }
+    if uses_vetkd(&capsule.content) {
+        return Err(CapsuleError::InvalidOperation {
+            reason: "Unlock date is bound into the capsule's vetKD key".to_string(),
+        });
+    }
+    if time_lock_passed(&capsule, current_time) {
+        return Err(CapsuleError::InvalidOperation {
+            reason: "Capsule has already reached its unlock date".to_string(),
+        });
+    }
+    if new_date <= capsule.unlock_date {
+        return Err(CapsuleError::InvalidPayload {
+            field: "new_date".to_string(),
+            reason: "New unlock date must be later than the current one".to_string(),
+        });
+    }
+
+    unschedule_capsule(&capsule);
+    unindex_capsule(&capsule);
+    capsule.unlock_date = new_date;
+    CAPSULE_STORAGE.with(|storage| {
+        storage.borrow_mut().insert(capsule_id, capsule.clone());
+    });
+    schedule_capsule(&capsule);
+    index_capsule(&capsule, current_time);
+    record_revision(&capsule, vec!["unlock_date".to_string()], current_time);
+
+    Ok(capsule)
+}
+
+// Revisions of a capsule, oldest first, for its creator and anyone who can open it
+#[ic_cdk::query]
+fn get_capsule_history(capsule_id: u64) -> Result<Vec<CapsuleRevision>, CapsuleError> {
+    let caller = ic_cdk::caller();
+
+    let capsule = CAPSULE_STORAGE
+        .with(|storage| storage.borrow().get(&capsule_id))
+        .ok_or(CapsuleError::NotFound)?;
+    if capsule.creator != caller {
+        readable_capsule(capsule_id, &caller)?;
+    }
+
+    Ok(CAPSULE_HISTORY.with(|history| {
+        history
+            .borrow()
+            .range(RevisionKey { capsule_id, revision: 0 }..)
+            .take_while(|(key, _)| key.capsule_id == capsule_id)
+            .map(|(_, revision)| revision)
+            .collect()
+    }))
+}
+
+// Checks shared by capsule creation and edits
+fn validate_capsule_fields(
+    content: &CapsuleContent,
+    unlock_date: u64,
+    access_control: &AccessControl,
+    metadata: &CapsuleMetadata,
+    current_time: u64,
+) -> Result<(), CapsuleError> {
+    if unlock_date <= current_time {
+        return Err(CapsuleError::InvalidPayload {
+            field: "unlock_date".to_string(),
+            reason: "Unlock date must be in the future".to_string(),
+        });
+    }
+
+    match content {
+        CapsuleContent::Committed { commitment } if commitment.len() != 32 => {
+            return Err(CapsuleError::InvalidPayload {
+                field: "content".to_string(),
+                reason: "Commitment must be a 32-byte SHA-256 digest".to_string(),
+            });
+        }
+        CapsuleContent::Committed { .. } => {}
+        _ => {
+            if contains_commitment(content) {
+                return Err(CapsuleError::InvalidPayload {
+                    field: "content".to_string(),
+                    reason: "A commitment must be the whole content of a capsule".to_string(),
+                });
+            }
+        }
+    }
+
+    validate_encrypted_content(content).map_err(|reason| CapsuleError::InvalidPayload {
+        field: "content".to_string(),
+        reason,
+    })?;
+
+    validate_access_control(access_control).map_err(|reason| CapsuleError::InvalidPayload {
+        field: "access_control".to_string(),
+        reason,
+    })?;
+
+    if metadata.tags.len() > MAX_TAGS {
+        return Err(CapsuleError::InvalidPayload {
+            field: "metadata.tags".to_string(),
+            reason: format!("A capsule can have at most {} tags", MAX_TAGS),
+        });
+    }
+    if metadata.tags.iter().any(|tag| normalize_tag(tag).len() > MAX_TAG_LENGTH) {
+        return Err(CapsuleError::InvalidPayload {
+            field: "metadata.tags".to_string(),
+            reason: format!("Tags can be at most {} bytes", MAX_TAG_LENGTH),
+        });
+    }
+
+    if let Some(location) = &metadata.location {
+        if !is_valid_coordinate(location.latitude, location.longitude) {
+            return Err(CapsuleError::InvalidPayload {
+                field: "metadata.location".to_string(),
+                reason: "Coordinates are out of range".to_string(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// Rejected here rather than trapping in the stable map
+fn check_capsule_size(capsule: &TimeCapsule) -> Result<(), CapsuleError> {
+    if capsule.to_bytes().len() > TimeCapsule::MAX_SIZE as usize {
+        return Err(CapsuleError::InvalidPayload {
+            field: "content".to_string(),
+            reason: "Capsule exceeds 1MB; upload large content with begin_upload and reference it as a Blob"
+                .to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Load a capsule created by the caller whose grace period is still running
+fn editable_capsule(capsule_id: u64, caller: &Principal, current_time: u64) -> Result<TimeCapsule, CapsuleError> {
+    let capsule = CAPSULE_STORAGE
+        .with(|storage| storage.borrow().get(&capsule_id))
+        .ok_or(CapsuleError::NotFound)?;
+    if capsule.creator != *caller {
+        return Err(CapsuleError::AccessDenied);
+    }
+    if capsule.editable_until.is_none_or(|until| current_time >= until) {
+        return Err(CapsuleError::InvalidOperation {
+            reason: "Capsule can no longer be edited".to_string(),
+        });
+    }
+    Ok(capsule)
+}
+
+// Commitment to content: SHA-256 of the length-prefixed salt followed by the canonical bytes
+// of the content. Clients compute it off-chain, so it must not depend on a serializer's choices
+fn commitment_hash(content: &CapsuleContent, salt: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    put_bytes(&mut bytes, salt);
+    canonical_content_bytes(content, &mut bytes);
+    Sha256::digest(&bytes).to_vec()
+}
+
+// Canonical bytes of content: a variant tag byte (Text 0, EncryptedMessage 1, MediaReference 2,
+// MultipartMessage 3, Blob 4, Committed 5), then the fields in declaration order. Text and byte
+// strings are prefixed with their length and integers written as big-endian u64; lists are
+// prefixed with their count, options with 0 or 1, algorithms are their variant index and
+// principals their raw bytes
+fn canonical_content_bytes(content: &CapsuleContent, out: &mut Vec<u8>) {
+    match content {
+        CapsuleContent::Text(text) => {
+            out.push(0);
+            put_bytes(out, text.as_bytes());
+        }
+        CapsuleContent::EncryptedMessage(envelope) => {
+            out.push(1);
+            out.push(match envelope.algorithm {
+                EncryptionAlgorithm::X25519XChaCha20Poly1305 => 0,
+                EncryptionAlgorithm::P256Aes256Gcm => 1,
+                EncryptionAlgorithm::VetKdIbe => 2,
+                EncryptionAlgorithm::Unspecified => 3,
+            });
+            put_bytes(out, &envelope.recipient_public_key);
+            put_bytes(out, &envelope.key_fingerprint);
+            put_bytes(out, &envelope.nonce);
+            put_bytes(out, &envelope.ciphertext);
+            put_bytes(out, &envelope.content_hash);
+            match &envelope.wrapped_keys {
+                None => out.push(0),
+                Some(wrapped_keys) => {
+                    out.push(1);
+                    out.extend_from_slice(&(wrapped_keys.len() as u64).to_be_bytes());
+                    for wrapped in wrapped_keys {
+                        put_bytes(out, wrapped.recipient.as_slice());
+                        put_bytes(out, &wrapped.key_fingerprint);
+                        put_bytes(out, &wrapped.wrapped_key);
+                    }
+                }
+            }
+        }
+        CapsuleContent::MediaReference { ipfs_hash, media_type } => {
+            out.push(2);
+            put_bytes(out, ipfs_hash.as_bytes());
+            put_bytes(out, media_type.as_bytes());
+        }
+        CapsuleContent::MultipartMessage { parts, title } => {
+            out.push(3);
+            out.extend_from_slice(&(parts.len() as u64).to_be_bytes());
+            for part in parts {
+                canonical_content_bytes(part, out);
+            }
+            put_bytes(out, title.as_bytes());
+        }
+        CapsuleContent::Blob { blob_id, media_type, size, sha256 } => {
+            out.push(4);
+            out.extend_from_slice(&blob_id.to_be_bytes());
+            put_bytes(out, media_type.as_bytes());
+            out.extend_from_slice(&size.to_be_bytes());
+            put_bytes(out, sha256);
+        }
+        CapsuleContent::Committed { commitment } => {
+            out.push(5);
+            put_bytes(out, commitment);
+        }
+    }
+}
+
+// Byte string prefixed with its length as a big-endian u64
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+// Check the structure and hashes of every encrypted envelope in the content
+fn validate_encrypted_content(content: &CapsuleContent) -> Result<(), String> {
+    match content {
+        CapsuleContent::EncryptedMessage(envelope) => validate_envelope(envelope),
+        CapsuleContent::MultipartMessage { parts, .. } => parts.iter().try_for_each(validate_encrypted_content),
+        _ => Ok(()),
+    }
+}
+
+fn validate_envelope(envelope: &EncryptedEnvelope) -> Result<(), String> {
+    let key = &envelope.recipient_public_key;
+    let nonce_len = match envelope.algorithm {
+        EncryptionAlgorithm::X25519XChaCha20Poly1305 => {
+            if key.len() != 32 {
+                return Err("X25519 public key must be 32 bytes".to_string());
+            }
+            24
+        }
+        EncryptionAlgorithm::P256Aes256Gcm => {
+            let uncompressed = key.len() == 65 && key[0] == 0x04;
+            let compressed = key.len() == 33 && (key[0] == 0x02 || key[0] == 0x03);
+            if !uncompressed && !compressed {
+                return Err("P-256 public key must be SEC1-encoded".to_string());
+            }
+            12
+        }
+        EncryptionAlgorithm::VetKdIbe => {
+            if key.len() != 96 {
+                return Err("vetKD public key must be a 96-byte BLS12-381 G2 point".to_string());
+            }
+            0
+        }
+        EncryptionAlgorithm::Unspecified => return Err("Encryption algorithm must be specified".to_string()),
+    };
+
+    if envelope.key_fingerprint != Sha256::digest(key).as_slice() {
+        return Err("Key fingerprint is not the SHA-256 of the public key".to_string());
+    }
+    if envelope.nonce.len() != nonce_len {
+        return Err(format!("Nonce must be {} bytes", nonce_len));
+    }
+    // AEAD ciphertexts end with a 16-byte tag
+    if envelope.ciphertext.len() < 16 {
+        return Err("Ciphertext is shorter than its authentication tag".to_string());
+    }
+    if envelope.content_hash != Sha256::digest(&envelope.ciphertext).as_slice() {
+        return Err("Content hash does not match the ciphertext".to_string());
+    }
+
+    if let Some(wrapped_keys) = &envelope.wrapped_keys {
+        if wrapped_keys.is_empty() || wrapped_keys.len() > MAX_WRAPPED_KEYS {
+            return Err(format!("Wrapped keys must list between 1 and {} viewers", MAX_WRAPPED_KEYS));
+        }
+        let mut recipients: Vec<&Principal> = wrapped_keys.iter().map(|key| &key.recipient).collect();
+        recipients.sort();
+        recipients.dedup();
+        if recipients.len() != wrapped_keys.len() {
+            return Err("Each viewer can have only one wrapped key".to_string());
+        }
+        for wrapped in wrapped_keys {
+            if wrapped.key_fingerprint.len() != 32 {
+                return Err(format!("Key fingerprint for {} must be a 32-byte SHA-256 digest", wrapped.recipient));
+            }
+            if wrapped.wrapped_key.is_empty() {
+                return Err(format!("Wrapped key for {} is empty", wrapped.recipient));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Drop the wrapped keys of other viewers before returning a capsule to the caller
+fn visible_to(mut capsule: TimeCapsule, caller: &Principal) -> TimeCapsule {
+    retain_caller_keys(&mut capsule.content, caller);
+    capsule
+}
+
+fn retain_caller_keys(content: &mut CapsuleContent, caller: &Principal) {
+    match content {
+        CapsuleContent::EncryptedMessage(envelope) => {
+            if let Some(wrapped_keys) = &mut envelope.wrapped_keys {
+                wrapped_keys.retain(|key| key.recipient == *caller);
+            }
+        }
+        CapsuleContent::MultipartMessage { parts, .. } => {
+            for part in parts {
+                retain_caller_keys(part, caller);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn contains_commitment(content: &CapsuleContent) -> bool {
+    match content {
+        CapsuleContent::Committed { .. } => true,
+        CapsuleContent::MultipartMessage { parts, .. } => parts.iter().any(contains_commitment),
+        _ => false,
+    }
+}
+
+// Append the capsule's current state to its history
+fn record_revision(capsule: &TimeCapsule, changed_fields: Vec<String>, current_time: u64) {
+    CAPSULE_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        let revision = history
+            .range(RevisionKey { capsule_id: capsule.id, revision: 0 }..)
+            .take_while(|(key, _)| key.capsule_id == capsule.id)
+            .count() as u32;
+        history.insert(
+            RevisionKey {
+                capsule_id: capsule.id,
+                revision,
+            },
+            CapsuleRevision {
+                revision,
+                changed_at: current_time,
+                changed_fields,
+                content_digest: Sha256::digest(Encode!(&capsule.content).unwrap()).to_vec(),
+                unlock_date: Some(capsule.unlock_date),
+            },
+        );
+    });
+}
+
+// Forget quiz passes, guardian approvals and verified unlocks recorded for a capsule
+fn clear_condition_progress(capsule_id: u64) {
+    QUIZ_PROGRESS.with(|progress| {
+        let mut progress = progress.borrow_mut();
+        let keys: Vec<QuizAttemptKey> = progress
+            .range(QuizAttemptKey { capsule_id, principal: Principal::from_slice(&[]) }..)
+            .take_while(|(key, _)| key.capsule_id == capsule_id)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            progress.remove(&key);
+        }
+    });
+
+    GUARDIAN_APPROVALS.with(|approvals| clear_guardian_approvals(&mut approvals.borrow_mut(), capsule_id));
+
+    VERIFIED_UNLOCKS.with(|unlocks| {
+        let mut unlocks = unlocks.borrow_mut();
+        let keys: Vec<VerifiedUnlockKey> = unlocks
+            .range(VerifiedUnlockKey { capsule_id, principal: Principal::from_slice(&[]) }..)
+            .take_while(|(key, _)| key.capsule_id == capsule_id)
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            unlocks.remove(&key);
+        }
+    });
+}
+
+fn clear_guardian_approvals(approvals: &mut StableBTreeMap<GuardianApprovalKey, u64, Memory>, capsule_id: u64) {
+    // The empty principal sorts before every other
+    let keys: Vec<GuardianApprovalKey> = approvals
+        .range(GuardianApprovalKey { capsule_id, guardian: Principal::from_slice(&[]) }..)
+        .take_while(|(key, _)| key.capsule_id == capsule_id)
+        .map(|(key, _)| key)
+        .collect();
+    for key in keys {
+        approvals.remove(&key);
+    }
+}
+
+// Retrieve a time capsule if conditions are met
+#[ic_cdk::query]
+fn get_capsule(capsule_id: u64) -> Result<TimeCapsule, CapsuleError> {
+    let caller = ic_cdk::caller();
+    readable_capsule(capsule_id, &caller).map(|capsule| visible_to(capsule, &caller))
+}
+
+// Summary of any capsule the caller may know about, sealed or not; never includes content
+#[ic_cdk::query]
+fn get_capsule_summary(capsule_id: u64) -> Result<CapsuleSummary, CapsuleError> {
+    let capsule = CAPSULE_STORAGE
+        .with(|storage| storage.borrow().get(&capsule_id))
+        .ok_or(CapsuleError::NotFound)?;
+    let caller = ic_cdk::caller();
+    if !knows_about(&capsule, &caller) {
+        return Err(CapsuleError::AccessDenied);
+    }
+    Ok(capsule_summary(&capsule, &caller, time()))
+}
+
+// Summaries of the caller's own capsules
+#[ic_cdk::query]
+fn get_my_capsule_summaries(page: PageRequest) -> CapsuleSummaryPage {
+    let creator = ic_cdk::caller();
+    let current_time = time();
+
+    let from = CreatorKey {
+        creator,
+        capsule_id: page.start_after.map_or(0, |id| id.saturating_add(1)),
+    };
+    let (items, next_cursor) = CREATOR_INDEX.with(|index| {
+        let index = index.borrow();
+        let ids = index
+            .range(from..)
+            .take_while(|(key, _)| key.creator == creator)
+            .map(|(key, _)| key.capsule_id);
+        paginate_ids(ids, &page, |capsule| Some(capsule_summary(&capsule, &creator, current_time)))
+    });
+
+    CapsuleSummaryPage { items, next_cursor }
+}
+
+// Summaries of other principals' capsules that name the caller as a viewer or guardian
+#[ic_cdk::query]
+fn get_shared_capsule_summaries(page: PageRequest) -> CapsuleSummaryPage {
+    let caller = ic_cdk::caller();
+    let current_time = time();
+
+    let from = ViewerKey {
+        viewer: caller,
+        capsule_id: page.start_after.map_or(0, |id| id.saturating_add(1)),
+    };
+    let (items, next_cursor) = VIEWER_INDEX.with(|index| {
+        let index = index.borrow();
+        let ids = index
+            .range(from..)
+            .take_while(|(key, _)| key.viewer == caller)
+            .map(|(key, _)| key.capsule_id);
+        paginate_ids(ids, &page, |capsule| Some(capsule_summary(&capsule, &caller, current_time)))
+    });
+
+    CapsuleSummaryPage { items, next_cursor }
+}
+
+// Public capsules are listed to everyone; others only to their creator and the principals they name
+fn knows_about(capsule: &TimeCapsule, caller: &Principal) -> bool {
+    matches!(capsule.access_control, AccessControl::Public) || capsule.creator == *caller || is_named_in(capsule, caller)
+}
+
+// Principals other than the creator that the capsule lists as a viewer or a guardian
+fn named_principals(capsule: &TimeCapsule) -> BTreeSet<Principal> {
+    let mut named = BTreeSet::new();
+    if let AccessControl::Private { allowed_viewers } = &capsule.access_control {
+        named.extend(allowed_viewers.iter().copied());
+    }
+    if let Some(quorum) = &capsule.early_unlock_guardians {
+        named.extend(quorum.guardians.iter().copied());
+    }
+    for condition in collect_conditions(&capsule.access_control) {
+        match condition {
+            UnlockCondition::Viewers { allowed_viewers } => named.extend(allowed_viewers.iter().copied()),
+            UnlockCondition::Guardians(quorum) => named.extend(quorum.guardians.iter().copied()),
+            _ => {}
+        }
+    }
+    named.remove(&capsule.creator);
+    named
+}
+
+// Whether the capsule lists the principal as a viewer or a guardian
+fn is_named_in(capsule: &TimeCapsule, principal: &Principal) -> bool {
+    if let AccessControl::Private { allowed_viewers } = &capsule.access_control {
+        if allowed_viewers.binary_search(principal).is_ok() {
+            return true;
+        }
+    }
+    if capsule
+        .early_unlock_guardians
+        .as_ref()
+        .is_some_and(|quorum| quorum.guardians.contains(principal))
+    {
+        return true;
+    }
+    collect_conditions(&capsule.access_control)
+        .into_iter()
+        .any(|condition| match condition {
+            UnlockCondition::Viewers { allowed_viewers } => allowed_viewers.binary_search(principal).is_ok(),
+            UnlockCondition::Guardians(quorum) => quorum.guardians.contains(principal),
+            _ => false,
+        })
+}
+
+// The unlock date has passed, or early-unlock guardians reached their quorum
+fn time_lock_passed(capsule: &TimeCapsule, current_time: u64) -> bool {
+    current_time >= capsule.unlock_date || capsule.early_unlocked_at.is_some()
+}
+
+// Load a capsule the caller may read without inter-canister calls, or whose conditions
+// the caller already satisfied through unlock_capsule
+fn readable_capsule(capsule_id: u64, caller: &Principal) -> Result<TimeCapsule, CapsuleError> {
+    let capsule = CAPSULE_STORAGE
+        .with(|storage| storage.borrow().get(&capsule_id))
+        .ok_or(CapsuleError::NotFound)?;
+    check_readable(&capsule, caller, time())?;
+    Ok(capsule)
+}
+
+// Check that the time lock has passed and the caller meets the conditions, counting a
+// recent pass through unlock_capsule for conditions a query can't check
+fn check_readable(capsule: &TimeCapsule, caller: &Principal, current_time: u64) -> Result<(), CapsuleError> {
+    if !time_lock_passed(capsule, current_time) {
+        return Err(CapsuleError::StillSealed {
+            unlock_date: capsule.unlock_date,
+        });
+    }
+
+    match check_access(capsule, caller, &ConditionContext::default()) {
+        Err(CapsuleError::ConditionFailed { .. }) if has_verified_unlock(capsule.id, caller, current_time) => Ok(()),
+        result => result,
+    }
+}
+
+fn has_verified_unlock(capsule_id: u64, caller: &Principal, current_time: u64) -> bool {
+    let key = VerifiedUnlockKey {
+        capsule_id,
+        principal: *caller,
+    };
+    VERIFIED_UNLOCKS.with(|unlocks| unlocks.borrow().get(&key).is_some_and(|expires_at| current_time < expires_at))
+}
+
+// Let a principal read a capsule through queries for VERIFIED_UNLOCK_TTL, dropping the
+// capsule's expired passes first; fails if MAX_VERIFIED_UNLOCKS other passes are current
+fn record_verified_unlock(capsule_id: u64, caller: Principal, current_time: u64) -> Result<(), CapsuleError> {
+    VERIFIED_UNLOCKS.with(|unlocks| {
+        let mut unlocks = unlocks.borrow_mut();
+        let entries: Vec<(VerifiedUnlockKey, u64)> = unlocks
+            .range(VerifiedUnlockKey { capsule_id, principal: Principal::from_slice(&[]) }..)
+            .take_while(|(key, _)| key.capsule_id == capsule_id)
+            .collect();
+
+        let mut current = 0;
+        for (key, expires_at) in entries {
+            if current_time >= expires_at {
+                unlocks.remove(&key);
+            } else if key.principal != caller {
+                current += 1;
+            }
+        }
+        if current >= MAX_VERIFIED_UNLOCKS {
+            return Err(CapsuleError::QuotaExceeded {
+                limit: MAX_VERIFIED_UNLOCKS as u64,
+            });
+        }
+
+        let key = VerifiedUnlockKey { capsule_id, principal: caller };
+        unlocks.insert(key, current_time.saturating_add(VERIFIED_UNLOCK_TTL));
+        Ok(())
+    })
+}
+
+// Describe the encrypted parts of a capsule the caller may know about, so recipients can check the key before unlock
+#[ic_cdk::query]
+fn get_encryption_info(capsule_id: u64) -> Result<Vec<EncryptionInfo>, CapsuleError> {
+    let capsule = CAPSULE_STORAGE
+        .with(|storage| storage.borrow().get(&capsule_id))
+        .ok_or(CapsuleError::NotFound)?;
+    if !knows_about(&capsule, &ic_cdk::caller()) {
+        return Err(CapsuleError::AccessDenied);
+    }
+
+    let mut envelopes = Vec::new();
+    collect_envelopes(&capsule.content, &mut envelopes);
+    Ok(envelopes
+        .into_iter()
+        .map(|envelope| EncryptionInfo {
+            algorithm: envelope.algorithm.clone(),
+            key_fingerprint: envelope.key_fingerprint.clone(),
+            content_hash: envelope.content_hash.clone(),
+            ciphertext_size: envelope.ciphertext.len() as u64,
+        })
+        .collect())
+}
+
+fn collect_envelopes<'a>(content: &'a CapsuleContent, envelopes: &mut Vec<&'a EncryptedEnvelope>) {
+    match content {
+        CapsuleContent::EncryptedMessage(envelope) => envelopes.push(envelope),
+        CapsuleContent::MultipartMessage { parts, .. } => {
+            for part in parts {
+                collect_envelopes(part, envelopes);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Public key that capsule content is IBE-encrypted to, under the input from vetkd_input
+#[ic_cdk::update]
+async fn get_vetkd_public_key() -> Result<Vec<u8>, CapsuleError> {
+    if let Some(public_key) = VETKD_PUBLIC_KEY.with(|key| key.borrow().clone()) {
+        return Ok(public_key);
+    }
+
+    let public_key = fetch_vetkd_public_key()
+        .await
+        .map_err(|reason| CapsuleError::InvalidOperation { reason })?;
+    VETKD_PUBLIC_KEY.with(|key| *key.borrow_mut() = Some(public_key.clone()));
+    Ok(public_key)
+}
+
+// Derive the capsule's decryption key, encrypted to the caller's transport key,
+// under the same time lock and access rules as unlock_capsule
+#[ic_cdk::update]
+async fn derive_capsule_key(
+    capsule_id: u64,
+    transport_public_key: Vec<u8>,
+    location: Option<GeoPoint>,
+) -> Result<Vec<u8>, CapsuleError> {
+    let caller = ic_cdk::caller();
+
+    // Transport keys are compressed BLS12-381 G1 points
+    if transport_public_key.len() != 48 {
+        return Err(CapsuleError::InvalidPayload {
+            field: "transport_public_key".to_string(),
+            reason: "Transport public key must be a 48-byte BLS12-381 G1 point".to_string(),
+        });
+    }
+
+    let capsule = CAPSULE_STORAGE
+        .with(|storage| storage.borrow().get(&capsule_id))
+        .ok_or(CapsuleError::NotFound)?;
+    if !time_lock_passed(&capsule, time()) {
+        return Err(CapsuleError::StillSealed {
+            unlock_date: capsule.unlock_date,
+        });
+    }
+    if !uses_vetkd(&capsule.content) {
+        return Err(CapsuleError::InvalidOperation {
+            reason: "Capsule content is not encrypted to its vetKD key".to_string(),
+        });
+    }
+
+    // Each derivation costs the canister about 26B cycles, which it only spends on principals
+    // the capsule names; anyone else who passes its conditions attaches the fee
+    let paid_by_caller = ic_cdk::api::call::msg_cycles_available128() >= VETKD_DERIVE_KEY_CYCLES;
+    if !paid_by_caller && !knows_about(&capsule, &caller) {
+        return Err(CapsuleError::InvalidOperation {
+            reason: format!("Attach {} cycles to derive this capsule's key", VETKD_DERIVE_KEY_CYCLES),
+        });
+    }
+
+    let context = gather_condition_context(&capsule.access_control, caller, location).await;
+    check_access(&capsule, &caller, &context)?;
+
+    // Counted before the call so that concurrent calls can't all pass the limit, and given
+    // back if the call fails, so only derived keys are charged
+    let charged_window = if paid_by_caller {
+        None
+    } else {
+        let started_at = KEY_DERIVATIONS
+            .with(|window| record_key_derivation(&mut window.borrow_mut(), caller, time()))
+            .map_err(|limit| CapsuleError::QuotaExceeded { limit })?;
+        Some(started_at)
+    };
+
+    let key = derive_vetkd_key(vetkd_input(&capsule), transport_public_key).await;
+    if key.is_ok() {
+        // Cycles that aren't accepted are refunded, so a failed call costs the caller nothing
+        if paid_by_caller {
+            ic_cdk::api::call::msg_cycles_accept128(VETKD_DERIVE_KEY_CYCLES);
+        }
+    } else if let Some(started_at) = charged_window {
+        KEY_DERIVATIONS.with(|window| release_key_derivation(&mut window.borrow_mut(), caller, started_at));
+    }
+    key.map_err(|reason| CapsuleError::InvalidOperation { reason })
+}
+
+// Count a derivation paid by the canister and return the start of its window, or return the hourly limit it would exceed
+fn record_key_derivation(window: &mut DerivationWindow, caller: Principal, current_time: u64) -> Result<u64, u64> {
+    if current_time >= window.started_at.saturating_add(KEY_DERIVATION_WINDOW) {
+        *window = DerivationWindow {
+            started_at: current_time,
+            ..DerivationWindow::default()
+        };
+    }
+
+    let count = window.by_caller.get(&caller).copied().unwrap_or(0);
+    if count >= MAX_KEY_DERIVATIONS_PER_CALLER {
+        return Err(MAX_KEY_DERIVATIONS_PER_CALLER.into());
+    }
+    if window.total >= MAX_KEY_DERIVATIONS {
+        return Err(MAX_KEY_DERIVATIONS.into());
+    }
+    window.by_caller.insert(caller, count + 1);
+    window.total += 1;
+    Ok(window.started_at)
+}
+
+// Give back a derivation counted by record_key_derivation, unless its window has ended since
+fn release_key_derivation(window: &mut DerivationWindow, caller: Principal, started_at: u64) {
+    if window.started_at != started_at {
+        return;
+    }
+    if let Some(count) = window.by_caller.get_mut(&caller) {
+        *count = count.saturating_sub(1);
+        window.total = window.total.saturating_sub(1);
+    }
+}
+
+// Identity a capsule's content is encrypted to: big-endian capsule ID, then big-endian unlock date
+fn vetkd_input(capsule: &TimeCapsule) -> Vec<u8> {
+    let mut input = capsule.id.to_be_bytes().to_vec();
+    input.extend_from_slice(&capsule.unlock_date.to_be_bytes());
+    input
+}
+
+fn uses_vetkd(content: &CapsuleContent) -> bool {
+    let mut envelopes = Vec::new();
+    collect_envelopes(content, &mut envelopes);
+    envelopes
+        .iter()
+        .any(|envelope| envelope.algorithm == EncryptionAlgorithm::VetKdIbe)
+}
+
+fn content_changed(changed_fields: &[String]) -> bool {
+    changed_fields.iter().any(|field| field == "content")
+}
+
+fn vetkd_key_id() -> VetKdKeyId {
+    VetKdKeyId {
+        curve: VetKdCurve::Bls12381G2,
+        name: VETKD_KEY_NAME.to_string(),
+    }
+}
+
+#[cfg(not(feature = "mock-vetkd"))]
+async fn fetch_vetkd_public_key() -> Result<Vec<u8>, String> {
+    let args = VetKdPublicKeyArgs {
+        canister_id: None,
+        context: VETKD_CONTEXT.to_vec(),
+        key_id: vetkd_key_id(),
+    };
+
+    let (result,): (VetKdPublicKeyResult,) = ic_cdk::call(Principal::management_canister(), "vetkd_public_key", (args,))
+        .await
+        .map_err(|(code, message)| format!("vetkd_public_key failed ({:?}): {}", code, message))?;
+
+    Ok(result.public_key)
+}
+
+#[cfg(not(feature = "mock-vetkd"))]
+async fn derive_vetkd_key(input: Vec<u8>, transport_public_key: Vec<u8>) -> Result<Vec<u8>, String> {
+    let args = VetKdDeriveKeyArgs {
+        input,
+        context: VETKD_CONTEXT.to_vec(),
+        transport_public_key,
+        key_id: vetkd_key_id(),
+    };
+
+    let (result,): (VetKdDeriveKeyResult,) = ic_cdk::api::call::call_with_payment128(
+        Principal::management_canister(),
+        "vetkd_derive_key",
+        (args,),
+        VETKD_DERIVE_KEY_CYCLES,
+    )
+    .await
+    .map_err(|(code, message)| format!("vetkd_derive_key failed ({:?}): {}", code, message))?;
+
+    Ok(result.encrypted_key)
+}
+
+// Deterministic stand-ins for the vetKD system API, for tests without a replica.
+// They provide no security and must never be deployed
+#[cfg(feature = "mock-vetkd")]
+async fn fetch_vetkd_public_key() -> Result<Vec<u8>, String> {
+    let mut public_key = Sha256::new()
+        .chain_update(b"mock-vetkd-public-key")
+        .chain_update(VETKD_CONTEXT)
+        .chain_update(vetkd_key_id().name)
+        .finalize()
+        .to_vec();
+    public_key.resize(96, 0);
+    Ok(public_key)
+}
+
+#[cfg(feature = "mock-vetkd")]
+async fn derive_vetkd_key(input: Vec<u8>, transport_public_key: Vec<u8>) -> Result<Vec<u8>, String> {
+    let mut encrypted_key = Sha256::new()
+        .chain_update(b"mock-vetkd-derive-key")
+        .chain_update(VETKD_CONTEXT)
+        .chain_update(input)
+        .chain_update(transport_public_key)
+        .finalize()
+        .to_vec();
+    encrypted_key.resize(192, 0);
+    Ok(encrypted_key)
+}
+
+// Retrieve a time capsule, verifying conditions that need inter-canister calls or caller input
+#[ic_cdk::update]
+async fn unlock_capsule(capsule_id: u64, location: Option<GeoPoint>) -> Result<TimeCapsule, CapsuleError> {
+    let caller = ic_cdk::caller();
+    let current_time = time();
+
+    let capsule = CAPSULE_STORAGE
+        .with(|storage| storage.borrow().get(&capsule_id))
+        .ok_or(CapsuleError::NotFound)?;
+
+    if !time_lock_passed(&capsule, current_time) {
+        return Err(CapsuleError::StillSealed {
+            unlock_date: capsule.unlock_date,
+        });
+    }
+
+    let context = gather_condition_context(&capsule.access_control, caller, location).await;
+    check_access(&capsule, &caller, &context)?;
+
+    // Queries can't repeat the ledger, collection or location checks, so record the pass for
+    // get_capsule and get_capsule_chunk. It expires because the caller may sell the tokens,
+    // hand the NFT on or move away, which only a fresh unlock_capsule would notice
+    if matches!(capsule.access_control, AccessControl::Conditional { .. } | AccessControl::ConditionTree { .. }) {
+        record_verified_unlock(capsule_id, caller, current_time)?;
+    }
+
+    // The first caller to satisfy the conditions completes a pending unlock
+    let mut capsule = capsule;
+    if matches!(capsule.status, CapsuleStatus::UnlockPending) {
+        capsule.status = CapsuleStatus::Unlocked;
+        CAPSULE_STORAGE.with(|storage| {
+            storage.borrow_mut().insert(capsule_id, capsule.clone());
+        });
+    }
+
+    Ok(visible_to(capsule, &caller))
+}
+
+// Check the capsule's access control for the caller
+fn check_access(capsule: &TimeCapsule, caller: &Principal, context: &ConditionContext) -> Result<(), CapsuleError> {
+    let result = match &capsule.access_control {
+        AccessControl::Public => Ok(()),
+        AccessControl::Private { allowed_viewers } => {
+            if capsule.creator == *caller || allowed_viewers.binary_search(caller).is_ok() {
+                Ok(())
+            } else {
+                return Err(CapsuleError::AccessDenied);
+            }
+        }
+        AccessControl::Conditional { condition } => validate_condition(capsule.id, condition, caller, context),
+        AccessControl::ConditionTree { root } => evaluate_condition_tree(capsule.id, root, caller, context),
+    };
+
+    result.map_err(|failure| match failure {
+        ConditionFailure::Unmet(reason) | ConditionFailure::Unverified(reason) => {
+            CapsuleError::ConditionFailed { reason }
+        }
+    })
+}
+
+// Evaluate a condition tree, explaining which branch failed
+fn evaluate_condition_tree(
+    capsule_id: u64,
+    expr: &ConditionExpr,
+    caller: &Principal,
+    context: &ConditionContext,
+) -> Result<(), ConditionFailure> {
+    match expr {
+        ConditionExpr::Condition(condition) => validate_condition(capsule_id, condition, caller, context),
+        ConditionExpr::And(branches) => {
+            for (index, branch) in branches.iter().enumerate() {
+                evaluate_condition_tree(capsule_id, branch, caller, context)
+                    .map_err(|failure| prefix_failure(failure, &format!("AND branch {}", index)))?;
+            }
+            Ok(())
+        }
+        ConditionExpr::Or(branches) => {
+            let mut reasons = Vec::new();
+            let mut unverified = false;
+            for (index, branch) in branches.iter().enumerate() {
+                match evaluate_condition_tree(capsule_id, branch, caller, context) {
+                    Ok(()) => return Ok(()),
+                    Err(ConditionFailure::Unmet(reason)) => reasons.push(format!("branch {}: {}", index, reason)),
+                    Err(ConditionFailure::Unverified(reason)) => {
+                        unverified = true;
+                        reasons.push(format!("branch {}: {}", index, reason));
+                    }
+                }
+            }
+
+            let reason = format!("no OR branch satisfied ({})", reasons.join("; "));
+            if unverified {
+                Err(ConditionFailure::Unverified(reason))
+            } else {
+                Err(ConditionFailure::Unmet(reason))
+            }
+        }
+        ConditionExpr::Not(inner) => match evaluate_condition_tree(capsule_id, inner, caller, context) {
+            Ok(()) => Err(ConditionFailure::Unmet("NOT: inner condition is satisfied".to_string())),
+            Err(ConditionFailure::Unmet(_)) => Ok(()),
+            Err(ConditionFailure::Unverified(reason)) => Err(ConditionFailure::Unverified(format!("NOT: {}", reason))),
+        },
+    }
+}
+
+// Prepend the failing branch to a failure reason
+fn prefix_failure(failure: ConditionFailure, branch: &str) -> ConditionFailure {
+    match failure {
+        ConditionFailure::Unmet(reason) => ConditionFailure::Unmet(format!("{}: {}", branch, reason)),
+        ConditionFailure::Unverified(reason) => ConditionFailure::Unverified(format!("{}: {}", branch, reason)),
+    }
+}
+
+// Function to validate conditional access
+fn validate_condition(
+    capsule_id: u64,
+    condition: &UnlockCondition,
+    caller: &Principal,
+    context: &ConditionContext,
+) -> Result<(), ConditionFailure> {
+    match condition {
+        UnlockCondition::TokenHolder(gate) => {
+            // Balances can only be checked with an inter-canister call
+            match context.token_balances.get(&(gate.ledger_canister_id, gate.subaccount.clone())) {
+                Some(Ok(balance)) if *balance >= gate.min_balance => Ok(()),
+                Some(Ok(_)) => Err(ConditionFailure::Unmet("Insufficient token balance".to_string())),
+                Some(Err(reason)) => Err(ConditionFailure::Unverified(reason.clone())),
+                None => Err(ConditionFailure::Unverified(
+                    "Token-gated capsule must be opened with unlock_capsule".to_string(),
+                )),
+            }
+        }
+        UnlockCondition::NftHolder(gate) => match context.nft_balances.get(&gate.collection_canister_id) {
+            Some(Ok(balance)) if *balance >= Nat::from(gate.min_tokens) => Ok(()),
+            Some(Ok(_)) => Err(ConditionFailure::Unmet("Insufficient NFT balance".to_string())),
+            Some(Err(reason)) => Err(ConditionFailure::Unverified(reason.clone())),
+            None => Err(ConditionFailure::Unverified(
+                "NFT-gated capsule must be opened with unlock_capsule".to_string(),
+            )),
+        },
+        UnlockCondition::GeoFence(fence) => match &context.location {
+            // The position is self-reported by the caller
+            Some(location) => {
+                let distance = calculate_distance(fence.latitude, fence.longitude, location.latitude, location.longitude);
+                if distance <= fence.radius_km {
+                    Ok(())
+                } else {
+                    Err(ConditionFailure::Unmet("Location is outside the geo-fence".to_string()))
+                }
+            }
+            None => Err(ConditionFailure::Unverified(
+                "Geo-fenced capsule must be opened with unlock_capsule and a location".to_string(),
+            )),
+        },
+        UnlockCondition::Quiz(_) => {
+            // Quiz answers are checked by submit_quiz_answers, which records the outcome
+            let key = QuizAttemptKey {
+                capsule_id,
+                principal: *caller,
+            };
+            let passed = QUIZ_PROGRESS.with(|progress| {
+                progress.borrow().get(&key).is_some_and(|p| p.passed)
+            });
+
+            if passed {
+                Ok(())
+            } else {
+                Err(ConditionFailure::Unmet("Quiz has not been passed".to_string()))
+            }
+        }
+        UnlockCondition::Timestamp { not_before } => {
+            if time() >= *not_before {
+                Ok(())
+            } else {
+                Err(ConditionFailure::Unmet("Condition timestamp has not been reached".to_string()))
+            }
+        }
+        UnlockCondition::Guardians(quorum) => {
+            let approvals = GUARDIAN_APPROVALS
+                .with(|approvals| count_guardian_approvals(&approvals.borrow(), capsule_id, &quorum.guardians));
+            if approvals >= quorum.threshold {
+                Ok(())
+            } else {
+                Err(ConditionFailure::Unmet(format!(
+                    "{} of {} guardian approvals",
+                    approvals, quorum.threshold
+                )))
+            }
+        }
+        UnlockCondition::Viewers { allowed_viewers } => {
+            if allowed_viewers.binary_search(caller).is_ok() {
+                Ok(())
+            } else {
+                Err(ConditionFailure::Unmet("Caller is not an allowed viewer".to_string()))
+            }
+        }
+    }
+}
+
+// Validate the access control of a new capsule
+fn validate_access_control(access_control: &AccessControl) -> Result<(), String> {
+    match access_control {
+        AccessControl::Private { allowed_viewers } => validate_viewer_list(allowed_viewers)?,
+        AccessControl::Conditional { condition } => validate_unlock_condition(condition)?,
+        AccessControl::ConditionTree { root } => {
+            let mut nodes = 0;
+            validate_condition_tree(root, 1, &mut nodes)?;
+        }
+        AccessControl::Public => {}
+    }
+
+    // Quiz progress is tracked per capsule, so a capsule can only carry one quiz
+    let quizzes = collect_conditions(access_control)
+        .into_iter()
+        .filter(|condition| matches!(condition, UnlockCondition::Quiz(_)))
+        .count();
+    if quizzes > 1 {
+        return Err("A capsule can have at most one quiz".to_string());
+    }
+
+    Ok(())
+}
+
+// Validate the shape and conditions of a condition tree
+fn validate_condition_tree(expr: &ConditionExpr, depth: usize, nodes: &mut usize) -> Result<(), String> {
+    *nodes += 1;
+    if depth > MAX_CONDITION_DEPTH {
+        return Err(format!("Condition tree is deeper than {} levels", MAX_CONDITION_DEPTH));
+    }
+    if *nodes > MAX_CONDITION_NODES {
+        return Err(format!("Condition tree has more than {} nodes", MAX_CONDITION_NODES));
+    }
+
+    match expr {
+        ConditionExpr::Condition(condition) => validate_unlock_condition(condition),
+        ConditionExpr::And(branches) | ConditionExpr::Or(branches) => {
+            if branches.is_empty() {
+                return Err("AND/OR nodes need at least one branch".to_string());
+            }
+            for branch in branches {
+                validate_condition_tree(branch, depth + 1, nodes)?;
+            }
+            Ok(())
+        }
+        ConditionExpr::Not(inner) => validate_condition_tree(inner, depth + 1, nodes),
+    }
+}
+
+// Validate an unlock condition when a capsule is created
+fn validate_unlock_condition(condition: &UnlockCondition) -> Result<(), String> {
+    match condition {
+        UnlockCondition::TokenHolder(gate) => {
+            if let Some(subaccount) = &gate.subaccount {
+                if subaccount.len() != 32 {
+                    return Err("Subaccount must be 32 bytes".to_string());
+                }
+            }
+        }
+        UnlockCondition::NftHolder(gate) => {
+            if gate.min_tokens == 0 {
+                return Err("NFT gate must require at least one token".to_string());
+            }
+        }
+        UnlockCondition::GeoFence(fence) => {
+            if !(-90.0..=90.0).contains(&fence.latitude) || !(-180.0..=180.0).contains(&fence.longitude) {
+                return Err("Geo-fence center is out of range".to_string());
+            }
+            if !fence.radius_km.is_finite() || fence.radius_km <= 0.0 {
+                return Err("Geo-fence radius must be positive".to_string());
+            }
+        }
+        UnlockCondition::Quiz(quiz) => {
+            if quiz.questions.is_empty() {
+                return Err("Quiz must have at least one question".to_string());
+            }
+            if quiz.max_attempts == 0 {
+                return Err("Quiz must allow at least one attempt".to_string());
+            }
+            if quiz.questions.iter().any(|q| q.answer_hash.len() != 32) {
+                return Err("Quiz answer hash must be a SHA-256 digest".to_string());
+            }
+        }
+        UnlockCondition::Timestamp { .. } => {}
+        UnlockCondition::Guardians(quorum) => validate_guardian_quorum(quorum)?,
+        UnlockCondition::Viewers { allowed_viewers } => {
+            if allowed_viewers.is_empty() {
+                return Err("Viewer condition needs at least one viewer".to_string());
+            }
+            validate_viewer_list(allowed_viewers)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_viewer_list(viewers: &[Principal]) -> Result<(), String> {
+    if viewers.len() > MAX_ALLOWED_VIEWERS {
+        return Err(format!("At most {} viewers can be allowed", MAX_ALLOWED_VIEWERS));
+    }
+    if viewers.contains(&Principal::anonymous()) {
+        return Err("The anonymous principal can't be an allowed viewer".to_string());
+    }
+    Ok(())
+}
+
+// Sort and deduplicate every viewer list so membership checks can binary search
+fn normalize_access_control(access_control: &mut AccessControl) {
+    fn normalize_condition(condition: &mut UnlockCondition) {
+        if let UnlockCondition::Viewers { allowed_viewers } = condition {
+            allowed_viewers.sort();
+            allowed_viewers.dedup();
+        }
+    }
+
+    fn walk(expr: &mut ConditionExpr) {
+        match expr {
+            ConditionExpr::Condition(condition) => normalize_condition(condition),
+            ConditionExpr::And(branches) | ConditionExpr::Or(branches) => branches.iter_mut().for_each(walk),
+            ConditionExpr::Not(inner) => walk(inner),
+        }
+    }
+
+    match access_control {
+        AccessControl::Public => {}
+        AccessControl::Private { allowed_viewers } => {
+            allowed_viewers.sort();
+            allowed_viewers.dedup();
+        }
+        AccessControl::Conditional { condition } => normalize_condition(condition),
+        AccessControl::ConditionTree { root } => walk(root),
+    }
+}
+
+// Guardians must be unique and the threshold reachable
+fn validate_guardian_quorum(quorum: &GuardianQuorum) -> Result<(), String> {
+    let mut guardians = quorum.guardians.clone();
+    guardians.sort();
+    guardians.dedup();
+    if guardians.len() != quorum.guardians.len() {
+        return Err("Guardians must be unique".to_string());
+    }
+    if quorum.threshold == 0 || quorum.threshold as usize > guardians.len() {
+        return Err("Guardian threshold must be between 1 and the number of guardians".to_string());
+    }
+    Ok(())
+}
+
+// All unlock conditions referenced by an access control, in tree order
+fn collect_conditions(access_control: &AccessControl) -> Vec<&UnlockCondition> {
+    fn walk<'a>(expr: &'a ConditionExpr, conditions: &mut Vec<&'a UnlockCondition>) {
+        match expr {
+            ConditionExpr::Condition(condition) => conditions.push(condition),
+            ConditionExpr::And(branches) | ConditionExpr::Or(branches) => {
+                branches.iter().for_each(|branch| walk(branch, conditions))
+            }
+            ConditionExpr::Not(inner) => walk(inner, conditions),
+        }
+    }
+
+    let mut conditions = Vec::new();
+    match access_control {
+        AccessControl::Conditional { condition } => conditions.push(condition),
+        AccessControl::ConditionTree { root } => walk(root, &mut conditions),
+        _ => {}
+    }
+    conditions
+}
+
+// Fetch the balances needed by the capsule's token and NFT conditions
+async fn gather_condition_context(
+    access_control: &AccessControl,
+    caller: Principal,
+    location: Option<GeoPoint>,
+) -> ConditionContext {
+    let mut context = ConditionContext {
+        location,
+        ..Default::default()
+    };
+
+    for condition in collect_conditions(access_control) {
+        match condition {
+            UnlockCondition::TokenHolder(gate) => {
+                let key = (gate.ledger_canister_id, gate.subaccount.clone());
+                if let Entry::Vacant(entry) = context.token_balances.entry(key) {
+                    entry.insert(fetch_token_balance(gate, caller).await);
+                }
+            }
+            UnlockCondition::NftHolder(gate) => {
+                if let Entry::Vacant(entry) = context.nft_balances.entry(gate.collection_canister_id) {
+                    entry.insert(fetch_nft_balance(gate, caller).await);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    context
+}
+
+// Hash an answer the same way creators do: SHA-256(salt || trimmed, lowercased answer)
+fn hash_quiz_answer(salt: &[u8], answer: &str) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(salt);
+    hasher.update(answer.trim().to_lowercase().as_bytes());
+    hasher.finalize().to_vec()
+}
+
+// Count approvals recorded by the given guardians
+fn count_guardian_approvals(
+    approvals: &StableBTreeMap<GuardianApprovalKey, u64, Memory>,
+    capsule_id: u64,
+    guardians: &[Principal],
+) -> u32 {
+    guardians
+        .iter()
+        .filter(|guardian| {
+            approvals.contains_key(&GuardianApprovalKey {
+                capsule_id,
+                guardian: **guardian,
+            })
+        })
+        .count() as u32
+}
+
+// Get the caller's balance on an ICRC-1 ledger
+async fn fetch_token_balance(gate: &TokenGate, caller: Principal) -> Result<Nat, String> {
+    let account = Account {
+        owner: caller,
+        subaccount: gate.subaccount.clone(),
+    };
+
+    let (balance,): (Nat,) = ic_cdk::call(gate.ledger_canister_id, "icrc1_balance_of", (account,))
+        .await
+        .map_err(|(code, message)| format!("Ledger call failed ({:?}): {}", code, message))?;
+
+    Ok(balance)
+}
+
+// Get the caller's token count in an ICRC-7 collection
+async fn fetch_nft_balance(gate: &NftGate, caller: Principal) -> Result<Nat, String> {
+    let account = Account {
+        owner: caller,
+        subaccount: None,
+    };
+
+    let (balances,): (Vec<Nat>,) = ic_cdk::call(gate.collection_canister_id, "icrc7_balance_of", (vec![account],))
+        .await
+        .map_err(|(code, message)| format!("Collection call failed ({:?}): {}", code, message))?;
+
+    balances
+        .into_iter()
+        .next()
+        .ok_or_else(|| "Collection returned no balance".to_string())
+}
+
+// Start a chunked upload of a blob with the given size and SHA-256
+#[ic_cdk::update]
+fn begin_upload(payload: BeginUploadPayload) -> Result<u64, CapsuleError> {
+    let caller = ic_cdk::caller();
+    let current_time = time();
+
+    // Quotas are per principal, so anonymous callers would all share one
+    if caller == Principal::anonymous() {
+        return Err(CapsuleError::AccessDenied);
+    }
+    if payload.size == 0 || payload.size > MAX_BLOB_SIZE {
+        return Err(CapsuleError::InvalidPayload {
+            field: "size".to_string(),
+            reason: format!("Blob size must be between 1 and {} bytes", MAX_BLOB_SIZE),
+        });
+    }
+    if payload.sha256.len() != 32 {
+        return Err(CapsuleError::InvalidPayload {
+            field: "sha256".to_string(),
+            reason: "Expected a 32-byte SHA-256 digest".to_string(),
+        });
+    }
+
+    let pending: Vec<BlobRecord> = pending_uploads_of(&caller)
+        .into_iter()
+        .filter(|blob| !upload_expired(blob, current_time))
+        .collect();
+    if pending.len() >= MAX_PENDING_UPLOADS {
+        return Err(CapsuleError::QuotaExceeded {
+            limit: MAX_PENDING_UPLOADS as u64,
+        });
+    }
+    if pending.iter().map(|blob| blob.size).sum::<u64>() + payload.size > MAX_PENDING_UPLOAD_BYTES {
+        return Err(CapsuleError::QuotaExceeded {
+            limit: MAX_PENDING_UPLOAD_BYTES,
+        });
+    }
+
+    let blob_id = BLOB_ID_COUNTER.with(|counter| {
+        let current_value = *counter.borrow().get();
+        counter.borrow_mut().set(current_value + 1)
+            .expect("Failed to increment blob counter");
+        current_value
+    });
+
+    let mut blob = BlobRecord {
+        owner: caller,
+        size: payload.size,
+        sha256: payload.sha256,
+        expires_at: None,
+        finalized: false,
+        capsule_id: None,
+    };
+    release_blob(blob_id, &mut blob, current_time);
+    BLOBS.with(|blobs| blobs.borrow_mut().insert(blob_id, blob));
+
+    Ok(blob_id)
+}
+
+// Store one chunk of a pending upload; every chunk but the last is BLOB_CHUNK_SIZE bytes
+#[ic_cdk::update]
+fn upload_chunk(blob_id: u64, index: u32, data: Vec<u8>) -> Result<(), CapsuleError> {
+    let blob = pending_upload(blob_id, &ic_cdk::caller())?;
+
+    let expected_len = chunk_len(blob.size, index).ok_or_else(|| CapsuleError::InvalidPayload {
+        field: "index".to_string(),
+        reason: format!("Blob has {} chunks", chunk_count(blob.size)),
+    })?;
+    if data.len() as u64 != expected_len {
+        return Err(CapsuleError::InvalidPayload {
+            field: "data".to_string(),
+            reason: format!("Chunk {} must be {} bytes", index, expected_len),
+        });
+    }
+
+    BLOB_CHUNKS.with(|chunks| chunks.borrow_mut().insert(BlobChunkKey { blob_id, index }, BlobChunk(data)));
+    Ok(())
+}
+
+// Verify a complete upload against its declared SHA-256 so capsules can reference it
+#[ic_cdk::update]
+fn finalize_upload(blob_id: u64) -> Result<(), CapsuleError> {
+    let mut blob = pending_upload(blob_id, &ic_cdk::caller())?;
+
+    let mut hasher = Sha256::new();
+    for index in 0..chunk_count(blob.size) {
+        let chunk = BLOB_CHUNKS
+            .with(|chunks| chunks.borrow().get(&BlobChunkKey { blob_id, index }))
+            .ok_or_else(|| CapsuleError::InvalidOperation {
+                reason: format!("Chunk {} has not been uploaded", index),
+            })?;
+        hasher.update(&chunk.0);
+    }
+    if hasher.finalize().as_slice() != blob.sha256.as_slice() {
+        return Err(CapsuleError::InvalidPayload {
+            field: "sha256".to_string(),
+            reason: "Uploaded data does not match the declared SHA-256".to_string(),
+        });
+    }
+
+    blob.finalized = true;
+    BLOBS.with(|blobs| blobs.borrow_mut().insert(blob_id, blob));
+    Ok(())
+}
+
+// Discard an upload, finished or not, that no capsule references
+#[ic_cdk::update]
+fn cancel_upload(blob_id: u64) -> Result<(), CapsuleError> {
+    let blob = BLOBS
+        .with(|blobs| blobs.borrow().get(&blob_id))
+        .ok_or(CapsuleError::NotFound)?;
+    if blob.owner != ic_cdk::caller() {
+        return Err(CapsuleError::AccessDenied);
+    }
+    if blob.capsule_id.is_some() {
+        return Err(CapsuleError::InvalidOperation {
+            reason: "Blob is referenced by a capsule".to_string(),
+        });
+    }
+
+    unrelease_blob(blob_id, &blob);
+    remove_blob(blob_id, blob.size);
+    Ok(())
+}
+
+// Read one chunk of a blob in a capsule, under the same sealing and access rules as get_capsule
+#[ic_cdk::query]
+fn get_capsule_chunk(capsule_id: u64, blob_id: u64, index: u32) -> Result<Vec<u8>, CapsuleError> {
+    let capsule = readable_capsule(capsule_id, &ic_cdk::caller())?;
+
+    let mut refs = Vec::new();
+    collect_blob_refs(&capsule.content, &mut refs);
+    if !refs.iter().any(|(id, _, _)| *id == blob_id) {
+        return Err(CapsuleError::NotFound);
+    }
+
+    BLOB_CHUNKS
+        .with(|chunks| chunks.borrow().get(&BlobChunkKey { blob_id, index }))
+        .map(|chunk| chunk.0)
+        .ok_or(CapsuleError::NotFound)
+}
+
+// Load an unfinalized upload owned by the caller
+fn pending_upload(blob_id: u64, caller: &Principal) -> Result<BlobRecord, CapsuleError> {
+    let blob = BLOBS
+        .with(|blobs| blobs.borrow().get(&blob_id))
+        .ok_or(CapsuleError::NotFound)?;
+    if blob.owner != *caller {
+        return Err(CapsuleError::AccessDenied);
+    }
+    if blob.finalized {
+        return Err(CapsuleError::InvalidOperation {
+            reason: "Upload is already finalized".to_string(),
+        });
+    }
+    if upload_expired(&blob, time()) {
+        return Err(CapsuleError::InvalidOperation {
+            reason: "Upload has expired".to_string(),
+        });
+    }
+    Ok(blob)
+}
+
+// Blobs of a principal that no capsule references, including expired ones the sweep hasn't reached
+fn pending_uploads_of(owner: &Principal) -> Vec<BlobRecord> {
+    let blob_ids: Vec<u64> = PENDING_UPLOADS.with(|pending| {
+        pending
+            .borrow()
+            .range(PendingUploadKey { owner: *owner, blob_id: 0 }..)
+            .take_while(|(key, _)| key.owner == *owner)
+            .map(|(key, _)| key.blob_id)
+            .collect()
+    });
+    BLOBS.with(|blobs| {
+        let blobs = blobs.borrow();
+        blob_ids.iter().filter_map(|blob_id| blobs.get(blob_id)).collect()
+    })
+}
+
+fn upload_expired(blob: &BlobRecord, current_time: u64) -> bool {
+    blob.expires_at.is_some_and(|expires_at| current_time >= expires_at)
+}
+
+// Run the periodic sweeps of expired uploads and reservations
+fn sweep_expired() {
+    sweep_expired_uploads();
+    sweep_expired_reservations();
+}
+
+// Discard blobs that no capsule referenced within UPLOAD_TTL, finalized or not
+fn sweep_expired_uploads() {
+    let current_time = time();
+    let expired: Vec<UploadExpiryKey> = UPLOAD_EXPIRY.with(|expiry| {
+        expiry
+            .borrow()
+            .iter()
+            .map(|(key, _)| key)
+            .take_while(|key| key.expires_at <= current_time)
+            .take(SWEEP_BATCH_SIZE)
+            .collect()
+    });
+
+    for key in &expired {
+        UPLOAD_EXPIRY.with(|expiry| expiry.borrow_mut().remove(key));
+        if let Some(blob) = BLOBS.with(|blobs| blobs.borrow().get(&key.blob_id)) {
+            PENDING_UPLOADS.with(|pending| {
+                pending.borrow_mut().remove(&PendingUploadKey { owner: blob.owner, blob_id: key.blob_id })
+            });
+            remove_blob(key.blob_id, blob.size);
+        }
+    }
+
+    if expired.len() == SWEEP_BATCH_SIZE {
+        ic_cdk_timers::set_timer(Duration::ZERO, sweep_expired_uploads);
+    }
+}
+
+// Count a blob no capsule references against its owner's quota, and expire it after UPLOAD_TTL
+fn release_blob(blob_id: u64, blob: &mut BlobRecord, current_time: u64) {
+    let expires_at = current_time.saturating_add(UPLOAD_TTL);
+    blob.expires_at = Some(expires_at);
+    PENDING_UPLOADS.with(|pending| pending.borrow_mut().insert(PendingUploadKey { owner: blob.owner, blob_id }, ()));
+    UPLOAD_EXPIRY.with(|expiry| expiry.borrow_mut().insert(UploadExpiryKey { expires_at, blob_id }, ()));
+}
+
+// Stop counting a blob against the quota and drop it from the sweep queue
+fn unrelease_blob(blob_id: u64, blob: &BlobRecord) {
+    PENDING_UPLOADS.with(|pending| pending.borrow_mut().remove(&PendingUploadKey { owner: blob.owner, blob_id }));
+    if let Some(expires_at) = blob.expires_at {
+        UPLOAD_EXPIRY.with(|expiry| expiry.borrow_mut().remove(&UploadExpiryKey { expires_at, blob_id }));
+    }
+}
+
+fn remove_blob(blob_id: u64, size: u64) {
+    BLOBS.with(|blobs| blobs.borrow_mut().remove(&blob_id));
+    BLOB_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+        for index in 0..chunk_count(size) {
+            chunks.remove(&BlobChunkKey { blob_id, index });
+        }
+    });
+}
+
+// Mark blobs as used by a capsule
+fn attach_blobs(capsule_id: u64, blob_ids: &[u64]) {
+    for blob_id in blob_ids {
+        if let Some(mut blob) = BLOBS.with(|blobs| blobs.borrow().get(blob_id)) {
+            unrelease_blob(*blob_id, &blob);
+            blob.capsule_id = Some(capsule_id);
+            blob.expires_at = None;
+            BLOBS.with(|blobs| blobs.borrow_mut().insert(*blob_id, blob));
+        }
+    }
+}
+
+// Release the blobs of replaced content so their owner can reuse or cancel them within UPLOAD_TTL
+fn detach_blobs(content: &CapsuleContent, current_time: u64) {
+    let mut refs = Vec::new();
+    collect_blob_refs(content, &mut refs);
+    for (blob_id, _, _) in refs {
+        if let Some(mut blob) = BLOBS.with(|blobs| blobs.borrow().get(&blob_id)) {
+            blob.capsule_id = None;
+            release_blob(blob_id, &mut blob, current_time);
+            BLOBS.with(|blobs| blobs.borrow_mut().insert(blob_id, blob));
+        }
+    }
+}
+
+fn chunk_count(size: u64) -> u32 {
+    size.div_ceil(BLOB_CHUNK_SIZE) as u32
+}
+
+// Expected length of a chunk, or None past the end of the blob
+fn chunk_len(size: u64, index: u32) -> Option<u64> {
+    let start = u64::from(index) * BLOB_CHUNK_SIZE;
+    (start < size).then(|| (size - start).min(BLOB_CHUNK_SIZE))
+}
+
+// Blob references (ID, size, SHA-256) in content, including multipart parts
+fn collect_blob_refs<'a>(content: &'a CapsuleContent, refs: &mut Vec<(u64, u64, &'a [u8])>) {
+    match content {
+        CapsuleContent::Blob { blob_id, size, sha256, .. } => refs.push((*blob_id, *size, sha256)),
+        CapsuleContent::MultipartMessage { parts, .. } => {
+            for part in parts {
+                collect_blob_refs(part, refs);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Check that referenced blobs are finalized, unexpired uploads of the caller not used by another capsule; returns their IDs
+fn validate_blob_refs(
+    content: &CapsuleContent,
+    caller: &Principal,
+    capsule_id: Option<u64>,
+    current_time: u64,
+) -> Result<Vec<u64>, String> {
+    let mut refs = Vec::new();
+    collect_blob_refs(content, &mut refs);
+
+    let mut blob_ids = Vec::new();
+    for (blob_id, size, sha256) in refs {
+        if blob_ids.contains(&blob_id) {
+            return Err(format!("Blob {} is referenced more than once", blob_id));
+        }
+        let blob = BLOBS
+            .with(|blobs| blobs.borrow().get(&blob_id))
+            .ok_or_else(|| format!("Blob {} does not exist", blob_id))?;
+        if blob.owner != *caller {
+            return Err(format!("Blob {} was not uploaded by the caller", blob_id));
+        }
+        if !blob.finalized {
+            return Err(format!("Blob {} upload is not finalized", blob_id));
+        }
+        if upload_expired(&blob, current_time) {
+            return Err(format!("Blob {} upload has expired", blob_id));
+        }
+        if blob.capsule_id.is_some() && blob.capsule_id != capsule_id {
+            return Err(format!("Blob {} is already used by another capsule", blob_id));
+        }
+        if blob.size != size || blob.sha256 != sha256 {
+            return Err(format!("Blob {} size or SHA-256 does not match the upload", blob_id));
+        }
+        blob_ids.push(blob_id);
+    }
+    Ok(blob_ids)
+}
+
+// Get the questions of a quiz-gated capsule the caller may know about, without salts or answer hashes
+#[ic_cdk::query]
+fn get_quiz_questions(capsule_id: u64) -> Result<Vec<String>, CapsuleError> {
+    let capsule = CAPSULE_STORAGE
+        .with(|storage| storage.borrow().get(&capsule_id))
+        .ok_or(CapsuleError::NotFound)?;
+    if !knows_about(&capsule, &ic_cdk::caller()) {
+        return Err(CapsuleError::AccessDenied);
+    }
+
+    match find_quiz(&capsule.access_control) {
+        Some(quiz) => Ok(quiz.questions.iter().map(|q| q.question.clone()).collect()),
+        None => Err(CapsuleError::InvalidOperation {
+            reason: "Capsule is not quiz-gated".to_string(),
+        }),
+    }
+}
+
+// Submit answers to a quiz-gated capsule, recording success for the caller
+#[ic_cdk::update]
+fn submit_quiz_answers(capsule_id: u64, answers: Vec<String>) -> Result<(), CapsuleError> {
+    let caller = ic_cdk::caller();
+
+    // Attempts are counted per principal, so anonymous callers would all share one count
+    if caller == Principal::anonymous() {
+        return Err(CapsuleError::AccessDenied);
+    }
+    let capsule = CAPSULE_STORAGE
+        .with(|storage| storage.borrow().get(&capsule_id))
+        .ok_or(CapsuleError::NotFound)?;
+
+    let quiz = find_quiz(&capsule.access_control)
+        .cloned()
+        .ok_or_else(|| CapsuleError::InvalidOperation {
+            reason: "Capsule is not quiz-gated".to_string(),
+        })?;
+
+    if answers.len() != quiz.questions.len() {
+        return Err(CapsuleError::InvalidPayload {
+            field: "answers".to_string(),
+            reason: format!("Expected {} answers", quiz.questions.len()),
+        });
+    }
+
+    let key = QuizAttemptKey {
+        capsule_id,
+        principal: caller,
+    };
+    let mut progress = QUIZ_PROGRESS
+        .with(|progress| progress.borrow().get(&key))
+        .unwrap_or_default();
+
+    let result = grade_quiz_attempt(&quiz, &mut progress, &answers);
+    QUIZ_PROGRESS.with(|storage| {
+        storage.borrow_mut().insert(key, progress);
+    });
+    result
+}
+
+// Grade one attempt against the quiz, counting it in the caller's progress
+fn grade_quiz_attempt(quiz: &QuizChallenge, progress: &mut QuizProgress, answers: &[String]) -> Result<(), CapsuleError> {
+    if progress.passed {
+        return Ok(());
+    }
+    if progress.attempts >= quiz.max_attempts {
+        return Err(CapsuleError::QuotaExceeded {
+            limit: quiz.max_attempts as u64,
+        });
+    }
+
+    progress.attempts += 1;
+    progress.passed = quiz
+        .questions
+        .iter()
+        .zip(answers)
+        .all(|(question, answer)| question.answer_hash == hash_quiz_answer(&question.salt, answer));
+
+    if progress.passed {
+        Ok(())
+    } else {
+        Err(CapsuleError::IncorrectAnswers {
+            attempts_remaining: quiz.max_attempts - progress.attempts,
+        })
+    }
+}
+
+// The quiz of a capsule, if it has one
+fn find_quiz(access_control: &AccessControl) -> Option<&QuizChallenge> {
+    collect_conditions(access_control)
+        .into_iter()
+        .find_map(|condition| match condition {
+            UnlockCondition::Quiz(quiz) => Some(quiz),
+            _ => None,
+        })
+}
+
+// Record the caller's approval as a guardian of a capsule
+#[ic_cdk::update]
+fn approve_capsule(capsule_id: u64) -> Result<(), CapsuleError> {
+    record_condition_approval(capsule_id, ic_cdk::caller(), time())
+}
+
+fn record_condition_approval(capsule_id: u64, caller: Principal, current_time: u64) -> Result<(), CapsuleError> {
+    let capsule = CAPSULE_STORAGE
+        .with(|storage| storage.borrow().get(&capsule_id))
+        .ok_or(CapsuleError::NotFound)?;
+
+    let quorums: Vec<&GuardianQuorum> = collect_conditions(&capsule.access_control)
+        .into_iter()
+        .filter_map(|condition| match condition {
+            UnlockCondition::Guardians(quorum) => Some(quorum),
+            _ => None,
+        })
+        .collect();
+
+    if quorums.is_empty() {
+        return Err(CapsuleError::InvalidOperation {
+            reason: "Capsule is not guardian-gated".to_string(),
+        });
+    }
+    if !quorums.iter().any(|quorum| quorum.guardians.contains(&caller)) {
+        return Err(CapsuleError::AccessDenied);
+    }
+
+    GUARDIAN_APPROVALS.with(|approvals| {
+        approvals.borrow_mut().insert(
+            GuardianApprovalKey {
+                capsule_id,
+                guardian: caller,
+            },
+            current_time,
+        );
+    });
+
+    Ok(())
+}
+
+// Reset the dead man's switch of a capsule, returning the next check-in deadline
+#[ic_cdk::update]
+fn heartbeat_checkin(capsule_id: u64) -> Result<u64, CapsuleError> {
+    let deadline = record_checkin(capsule_id, ic_cdk::caller(), time())?;
+    arm_unlock_timer();
+    Ok(deadline)
+}
+
+fn record_checkin(capsule_id: u64, caller: Principal, current_time: u64) -> Result<u64, CapsuleError> {
+    let mut capsule = CAPSULE_STORAGE
+        .with(|storage| storage.borrow().get(&capsule_id))
+        .ok_or(CapsuleError::NotFound)?;
+    if capsule.creator != caller {
+        return Err(CapsuleError::AccessDenied);
+    }
+    let Some(switch) = capsule.dead_man_switch.clone() else {
+        return Err(CapsuleError::InvalidOperation {
+            reason: "Capsule has no dead man's switch".to_string(),
+        });
+    };
+    if time_lock_passed(&capsule, current_time) {
+        return Err(CapsuleError::InvalidOperation {
+            reason: "Capsule is already past its time lock".to_string(),
+        });
+    }
+
+    // Checking in during the warning window seals the capsule again
+    unschedule_capsule(&capsule);
+    capsule.last_checkin = Some(current_time);
+    capsule.status = CapsuleStatus::Sealed;
+    CAPSULE_STORAGE.with(|storage| {
+        storage.borrow_mut().insert(capsule_id, capsule.clone());
+    });
+    schedule_capsule(&capsule);
+
+    Ok(current_time.saturating_add(switch.checkin_interval))
+}
+
+// Approve opening a capsule before its unlock date; the approval completing the quorum unlocks it
+#[ic_cdk::update]
+fn approve_early_unlock(capsule_id: u64) -> Result<(), CapsuleError> {
+    if record_early_unlock_approval(capsule_id, ic_cdk::caller(), time())? {
+        arm_unlock_timer();
+    }
+    Ok(())
+}
+
+// Record an early-unlock approval; returns whether it completed the quorum and unlocked the capsule
+fn record_early_unlock_approval(capsule_id: u64, caller: Principal, current_time: u64) -> Result<bool, CapsuleError> {
+    let mut capsule = CAPSULE_STORAGE
+        .with(|storage| storage.borrow().get(&capsule_id))
+        .ok_or(CapsuleError::NotFound)?;
+    let Some(quorum) = capsule.early_unlock_guardians.clone() else {
+        return Err(CapsuleError::InvalidOperation {
+            reason: "Capsule has no early-unlock guardians".to_string(),
+        });
+    };
+    if !quorum.guardians.contains(&caller) {
+        return Err(CapsuleError::AccessDenied);
+    }
+    if time_lock_passed(&capsule, current_time) {
+        return Err(CapsuleError::InvalidOperation {
+            reason: "Capsule is already past its time lock".to_string(),
+        });
+    }
+
+    let approvals = EARLY_UNLOCK_APPROVALS.with(|approvals| {
+        let mut approvals = approvals.borrow_mut();
+        approvals.insert(
+            GuardianApprovalKey {
+                capsule_id,
+                guardian: caller,
+            },
+            current_time,
+        );
+        count_guardian_approvals(&approvals, capsule_id, &quorum.guardians)
+    });
+
+    // Reaching the quorum is final; later revocations don't seal the capsule again
+    if approvals < quorum.threshold {
+        return Ok(false);
+    }
+
+    unschedule_capsule(&capsule);
+    capsule.early_unlocked_at = Some(current_time);
+    capsule.status = status_after_unlock_date(&capsule.access_control);
+    CAPSULE_STORAGE.with(|storage| {
+        storage.borrow_mut().insert(capsule_id, capsule.clone());
+    });
+    index_capsule(&capsule, current_time);
+
+    Ok(true)
+}
+
+// Withdraw the caller's guardian approvals for a capsule, early-unlock and condition approvals alike
+#[ic_cdk::update]
+fn revoke_approval(capsule_id: u64) -> Result<(), CapsuleError> {
+    withdraw_approvals(capsule_id, ic_cdk::caller())
+}
+
+fn withdraw_approvals(capsule_id: u64, guardian: Principal) -> Result<(), CapsuleError> {
+    let key = GuardianApprovalKey { capsule_id, guardian };
+
+    let revoked_early = EARLY_UNLOCK_APPROVALS.with(|approvals| approvals.borrow_mut().remove(&key));
+    let revoked_condition = GUARDIAN_APPROVALS.with(|approvals| approvals.borrow_mut().remove(&key));
+    if revoked_early.is_none() && revoked_condition.is_none() {
+        return Err(CapsuleError::InvalidOperation {
+            reason: "Caller has not approved this capsule".to_string(),
+        });
+    }
+
+    Ok(())
+}
+
+// Get public capsules that are unlocked
+#[ic_cdk::query]
+fn get_public_capsules(page: PageRequest) -> CapsulePage {
+    let caller = ic_cdk::caller();
+    let current_time = time();
+
+    let (items, next_cursor) = paginate_capsules(&page, |capsule| {
+        if matches!(capsule.access_control, AccessControl::Public) && time_lock_passed(&capsule, current_time) {
+            Some(visible_to(capsule, &caller))
+        } else {
+            None
+        }
+    });
+
+    CapsulePage { items, next_cursor }
+}
+
+// Get capsules by location the caller may know about, redacting those the caller can't open
+#[ic_cdk::query]
+fn get_capsules_by_location(latitude: f64, longitude: f64, radius_km: f64, page: LocationPageRequest) -> LocationPage {
+    let caller = ic_cdk::caller();
+    let current_time = time();
+
+    if !is_valid_coordinate(latitude, longitude) || radius_km.is_nan() || radius_km < 0.0 {
+        return LocationPage {
+            items: Vec::new(),
+            next_cursor: None,
+        };
+    }
+
+    // Index entries are read in key order from the cursor on, so a page scans at most MAX_PAGE_SCAN of them
+    let ranges = geo_key_ranges(geo_cell_ranges(latitude, longitude, radius_km), page.start_after.as_ref());
+    let (items, next_cursor) = GEO_INDEX.with(|index| {
+        let index = index.borrow();
+        let keys = ranges
+            .into_iter()
+            .flat_map(|(from, to)| index.range(from..to).map(|(key, _)| key));
+
+        CAPSULE_STORAGE.with(|storage| {
+            let storage = storage.borrow();
+            let capsules = keys.filter_map(|key| storage.get(&key.capsule_id).map(|capsule| (key, capsule)));
+
+            paginate(capsules, page.limit, |capsule| {
+                // Sealed capsules are matched by their coarsened position, so shrinking the radius reveals nothing more
+                let location = capsule.metadata.location.as_ref()?;
+                let (capsule_latitude, capsule_longitude) = public_position(location, &capsule, current_time);
+                if calculate_distance(latitude, longitude, capsule_latitude, capsule_longitude) <= radius_km {
+                    capsule_view(capsule, &caller, current_time)
+                } else {
+                    None
+                }
+            })
+        })
+    });
+
+    LocationPage { items, next_cursor }
+}
+
+// Get capsules created by a principal, redacting those the caller can't open
+#[ic_cdk::query]
+fn get_capsules_by_creator(creator: Principal, page: PageRequest) -> CapsuleViewPage {
+    let caller = ic_cdk::caller();
+    let current_time = time();
+
+    let from = CreatorKey {
+        creator,
+        capsule_id: page.start_after.map_or(0, |id| id.saturating_add(1)),
+    };
+    let (items, next_cursor) = CREATOR_INDEX.with(|index| {
+        let index = index.borrow();
+        let ids = index
+            .range(from..)
+            .take_while(|(key, _)| key.creator == creator)
+            .map(|(key, _)| key.capsule_id);
+        paginate_ids(ids, &page, |capsule| capsule_view(capsule, &caller, current_time))
+    });
+
+    CapsuleViewPage { items, next_cursor }
+}
+
+// Get capsules with a tag, ignoring case and a leading '#', redacting those the caller can't open
+#[ic_cdk::query]
+fn get_capsules_by_tag(tag: String, page: PageRequest) -> CapsuleViewPage {
+    let caller = ic_cdk::caller();
+    let current_time = time();
+    let tag = normalize_tag(&tag);
+
+    let from = TagKey {
+        tag: tag.clone(),
+        capsule_id: page.start_after.map_or(0, |id| id.saturating_add(1)),
+    };
+    let (items, next_cursor) = TAG_INDEX.with(|index| {
+        let index = index.borrow();
+        let ids = index
+            .range(from..)
+            .take_while(|(key, _)| key.tag == tag)
+            .map(|(key, _)| key.capsule_id);
+        paginate_ids(ids, &page, |capsule| capsule_view(capsule, &caller, current_time))
+    });
+
+    CapsuleViewPage { items, next_cursor }
+}
+
+// Get capsules whose unlock date is in [from, to), soonest first, redacting those the caller can't open
+#[ic_cdk::query]
+fn get_capsules_unlocking_between(from: u64, to: u64, page: UnlockDatePageRequest) -> UnlockDatePage {
+    let caller = ic_cdk::caller();
+    let current_time = time();
+
+    let from_key = UnlockDateKey {
+        unlock_date: from,
+        capsule_id: 0,
+    };
+    let to_key = UnlockDateKey {
+        unlock_date: to,
+        capsule_id: 0,
+    };
+    if from_key >= to_key || page.start_after.as_ref().is_some_and(|cursor| *cursor >= to_key) {
+        return UnlockDatePage {
+            items: Vec::new(),
+            next_cursor: None,
+        };
+    }
+    let start = match page.start_after {
+        Some(cursor) if cursor >= from_key => Bound::Excluded(cursor),
+        _ => Bound::Included(from_key),
+    };
+
+    let (items, next_cursor) = UNLOCK_DATE_INDEX.with(|index| {
+        let index = index.borrow();
+        CAPSULE_STORAGE.with(|storage| {
+            let storage = storage.borrow();
+            let capsules = index
+                .range((start, Bound::Excluded(to_key)))
+                .filter_map(|(key, _)| storage.get(&key.capsule_id).map(|capsule| (key, capsule)));
+            paginate(capsules, page.limit, |capsule| capsule_view(capsule, &caller, current_time))
+        })
+    });
+
+    UnlockDatePage { items, next_cursor }
+}
+
+// Search unlocked public capsules for all words of the query, best matches first within each batch.
+// Ranking is local to a batch of MAX_PAGE_SCAN candidates, so a better match from a later batch
+// can follow weaker ones; only a capsule's MAX_INDEXED_TERMS heaviest words can match at all
+#[ic_cdk::query]
+fn search_capsules(query: String, page: SearchPageRequest) -> SearchPage {
+    search_index(&query, page, &ic_cdk::caller())
+}
+
+fn search_index(query: &str, page: SearchPageRequest, caller: &Principal) -> SearchPage {
+    let limit = page.limit.clamp(1, MAX_PAGE_SIZE) as usize;
+
+    let mut terms: Vec<String> = tokenize(query).collect();
+    terms.sort();
+    terms.dedup();
+    terms.truncate(MAX_QUERY_TERMS);
+    if terms.is_empty() {
+        return SearchPage {
+            items: Vec::new(),
+            next_cursor: None,
+        };
+    }
+
+    // Candidates come from the term with the fewest postings, MAX_PAGE_SCAN at a time, so
+    // every capsule containing all terms is reached in some batch
+    let cursor = page.start_after.unwrap_or(SearchCursor {
+        batch_after: None,
+        last_hit: None,
+    });
+    let first_id = cursor.batch_after.map_or(0, |capsule_id| capsule_id.saturating_add(1));
+    let (mut ranked, batch_end) = SEARCH_INDEX.with(|index| {
+        let index = index.borrow();
+        let postings = |term: &String| {
+            let from = SearchKey {
+                term: term.clone(),
+                capsule_id: first_id,
+            };
+            let term = term.clone();
+            index.range(from..).take_while(move |(key, _)| key.term == term)
+        };
+        let Some(rarest) = terms.iter().min_by_key(|term| postings(term).take(MAX_PAGE_SCAN + 1).count()) else {
+            return (Vec::new(), None);
+        };
+
+        // Sum each candidate's term weights, keeping only capsules that contain every term
+        let mut scores = Vec::new();
+        let mut batch_end = None;
+        let mut last_id = None;
+        for (scanned, (key, weight)) in postings(rarest).enumerate() {
+            if scanned == MAX_PAGE_SCAN {
+                batch_end = last_id;
+                break;
+            }
+            last_id = Some(key.capsule_id);
+            let score = terms.iter().filter(|term| *term != rarest).try_fold(weight, |score, term| {
+                let key = SearchKey {
+                    term: term.clone(),
+                    capsule_id: key.capsule_id,
+                };
+                index.get(&key).map(|weight| score.saturating_add(weight))
+            });
+            if let Some(score) = score {
+                scores.push((score, key.capsule_id));
+            }
+        }
+        (scores, batch_end)
+    });
+
+    let rank = |score: u32, capsule_id: u64| (Reverse(score), capsule_id);
+    ranked.sort_by_key(|&(score, id)| rank(score, id));
+    let start = cursor.last_hit.map_or(0, |hit| {
+        ranked.partition_point(|&(score, id)| rank(score, id) <= rank(hit.score, hit.capsule_id))
+    });
+
+    // Once this batch is exhausted, the next page starts the next one
+    let mut next_cursor = batch_end.map(|capsule_id| SearchCursor {
+        batch_after: Some(capsule_id),
+        last_hit: None,
+    });
+    let mut items: Vec<SearchHit> = Vec::new();
+    let mut page_bytes = 0;
+    let mut page_full = false;
+    CAPSULE_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        for &(score, capsule_id) in &ranked[start..] {
+            if items.len() == limit {
+                page_full = true;
+                break;
+            }
+            let Some(capsule) = storage.get(&capsule_id).filter(is_searchable) else {
+                continue;
+            };
+            let hit = SearchHit {
+                capsule: visible_to(capsule, caller),
+                score,
+            };
+            page_bytes += encoded_size(&hit);
+            if !items.is_empty() && page_bytes > MAX_PAGE_BYTES {
+                page_full = true;
+                break;
+            }
+            items.push(hit);
+        }
+    });
+    // Stopped before the end of the batch, so the next page continues after the last hit
+    if page_full {
+        next_cursor = items.last().map(|hit| SearchCursor {
+            batch_after: cursor.batch_after,
+            last_hit: Some(SearchPosition {
+                score: hit.score,
+                capsule_id: hit.capsule.id,
+            }),
+        });
+    }
+
+    SearchPage { items, next_cursor }
+}
+
+fn normalize_tag(tag: &str) -> String {
+    tag.trim().trim_start_matches('#').to_lowercase()
+}
+
+// Page through CAPSULE_STORAGE in ID order, keeping the capsules `select` maps to an item
+fn paginate_capsules<T: candid::CandidType>(page: &PageRequest, select: impl FnMut(TimeCapsule) -> Option<T>) -> (Vec<T>, Option<u64>) {
+    let start = page.start_after.map_or(Bound::Unbounded, Bound::Excluded);
+    CAPSULE_STORAGE.with(|storage| paginate(storage.borrow().range((start, Bound::Unbounded)), page.limit, select))
+}
+
+// Page through the capsules with the given IDs, which an index yields in ascending order
+fn paginate_ids<T: candid::CandidType>(
+    ids: impl Iterator<Item = u64>,
+    page: &PageRequest,
+    select: impl FnMut(TimeCapsule) -> Option<T>,
+) -> (Vec<T>, Option<u64>) {
+    CAPSULE_STORAGE.with(|storage| {
+        let storage = storage.borrow();
+        let capsules = ids.filter_map(|id| storage.get(&id).map(|capsule| (id, capsule)));
+        paginate(capsules, page.limit, select)
+    })
+}
+
+// Page through capsules in cursor order, stopping at the page limit, the scan budget or the size budget
+fn paginate<K, T: candid::CandidType>(
+    capsules: impl Iterator<Item = (K, TimeCapsule)>,
+    limit: u32,
+    mut select: impl FnMut(TimeCapsule) -> Option<T>,
+) -> (Vec<T>, Option<K>) {
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let mut items = Vec::new();
+    let mut page_bytes = 0;
+    let mut last_scanned = None;
+
+    for (scanned, (cursor, capsule)) in capsules.enumerate() {
+        // Another capsule remains, so a cursor is only returned when there is more to read
+        if items.len() == limit || scanned == MAX_PAGE_SCAN {
+            return (items, last_scanned);
+        }
+
+        if let Some(item) = select(capsule) {
+            page_bytes += encoded_size(&item);
+            // The cursor stays before this capsule, so the next page starts with it
+            if !items.is_empty() && page_bytes > MAX_PAGE_BYTES {
+                return (items, last_scanned);
+            }
+            items.push(item);
+        }
+        last_scanned = Some(cursor);
+    }
+
+    (items, None)
+}
+
+fn encoded_size<T: candid::CandidType>(item: &T) -> usize {
+    Encode!(item).map_or(0, |bytes| bytes.len())
+}
+
+// Apply the same sealing and access rules as get_capsule; capsules the caller can't open
+// are summarized only if the caller may know about them, and left out otherwise
+fn capsule_view(capsule: TimeCapsule, caller: &Principal, current_time: u64) -> Option<CapsuleView> {
+    if check_readable(&capsule, caller, current_time).is_ok() {
+        Some(CapsuleView::Full(Box::new(visible_to(capsule, caller))))
+    } else if knows_about(&capsule, caller) {
+        Some(CapsuleView::Summary(capsule_summary(&capsule, caller, current_time)))
+    } else {
+        None
+    }
+}
+
+// Summary as the caller may see it, with metadata the creator sealed blanked out until the time lock passes
+fn capsule_summary(capsule: &TimeCapsule, caller: &Principal, current_time: u64) -> CapsuleSummary {
+    let content_kind = match &capsule.content {
+        CapsuleContent::Text(_) => ContentKind::Text,
+        CapsuleContent::EncryptedMessage(_) => ContentKind::EncryptedMessage,
+        CapsuleContent::MediaReference { .. } => ContentKind::MediaReference,
+        CapsuleContent::MultipartMessage { .. } => ContentKind::MultipartMessage,
+        CapsuleContent::Blob { .. } => ContentKind::Blob,
+        CapsuleContent::Committed { .. } => ContentKind::Committed,
+    };
+    let access_type = match &capsule.access_control {
+        AccessControl::Public => AccessType::Public,
+        AccessControl::Private { .. } => AccessType::Private,
+        AccessControl::Conditional { .. } => AccessType::Conditional,
+        AccessControl::ConditionTree { .. } => AccessType::ConditionTree,
+    };
+
+    let mut hidden_fields = Vec::new();
+    let mut hidden = |field: MetadataField| {
+        let sealed = capsule.creator != *caller && metadata_sealed(capsule, &field, current_time);
+        if sealed {
+            hidden_fields.push(field);
+        }
+        sealed
+    };
+    let title = if hidden(MetadataField::Title) {
+        String::new()
+    } else {
+        capsule.metadata.title.clone()
+    };
+    let tags = if hidden(MetadataField::Tags) {
+        Vec::new()
+    } else {
+        capsule.metadata.tags.clone()
+    };
+    let location = if hidden(MetadataField::Location) {
+        None
+    } else if capsule.creator == *caller {
+        capsule.metadata.location.clone()
+    } else {
+        capsule
+            .metadata
+            .location
+            .as_ref()
+            .map(|location| public_location(location, capsule, current_time))
+    };
+
+    CapsuleSummary {
+        id: capsule.id,
+        creator: capsule.creator,
+        title,
+        tags,
+        location,
+        unlock_date: capsule.unlock_date,
+        status: capsule.status.clone(),
+        content_kind,
+        content_size: content_size(&capsule.content),
+        access_type,
+        hidden_fields,
+    }
+}
+
+// A field listed in sealed_metadata stays hidden until the time lock passes
+fn metadata_sealed(capsule: &TimeCapsule, field: &MetadataField, current_time: u64) -> bool {
+    !time_lock_passed(capsule, current_time)
+        && capsule
+            .sealed_metadata
+            .as_ref()
+            .is_some_and(|fields| fields.contains(field))
+}
+
+fn content_size(content: &CapsuleContent) -> u64 {
+    match content {
+        CapsuleContent::Text(text) => text.len() as u64,
+        CapsuleContent::EncryptedMessage(envelope) => envelope.ciphertext.len() as u64,
+        CapsuleContent::MediaReference { .. } | CapsuleContent::Committed { .. } => 0,
+        CapsuleContent::MultipartMessage { parts, .. } => parts.iter().map(content_size).sum(),
+        CapsuleContent::Blob { size, .. } => *size,
+    }
+}
+
+// Queue a status check for a capsule at its unlock date; callers re-arm the timer afterwards
+fn schedule_capsule(capsule: &TimeCapsule) {
+    UNLOCK_QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        for due_at in scheduled_times(capsule) {
+            queue.insert(ScheduleKey { due_at, capsule_id: capsule.id }, ());
+        }
+    });
+}
+
+fn unschedule_capsule(capsule: &TimeCapsule) {
+    UNLOCK_QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        for due_at in scheduled_times(capsule) {
+            queue.remove(&ScheduleKey { due_at, capsule_id: capsule.id });
+        }
+    });
+}
+
+// Times at which the capsule's status may change: its unlock date, and the
+// start and end of the dead man's switch warning window
+fn scheduled_times(capsule: &TimeCapsule) -> Vec<u64> {
+    let mut times = vec![capsule.unlock_date];
+    if let (Some(switch), Some(last_checkin)) = (&capsule.dead_man_switch, capsule.last_checkin) {
+        let deadline = last_checkin.saturating_add(switch.checkin_interval);
+        times.push(deadline);
+        times.push(deadline.saturating_add(switch.warning_period));
+    }
+    times
+}
+
+// Arm the timer for the earliest queued unlock, replacing any pending timer
+fn arm_unlock_timer() {
+    let next_due = UNLOCK_QUEUE.with(|queue| queue.borrow().iter().next().map(|(key, _)| key.due_at));
+
+    UNLOCK_TIMER.with(|timer| {
+        let mut timer = timer.borrow_mut();
+        if let Some(timer_id) = timer.take() {
+            ic_cdk_timers::clear_timer(timer_id);
+        }
+        if let Some(due_at) = next_due {
+            let delay = Duration::from_nanos(due_at.saturating_sub(time()));
+            *timer = Some(ic_cdk_timers::set_timer(delay, process_unlock_queue));
+        }
+    });
+}
+
+// Move capsules whose unlock date has passed out of the Sealed state
+fn process_unlock_queue() {
+    UNLOCK_TIMER.with(|timer| timer.borrow_mut().take());
+    let current_time = time();
+
+    let due: Vec<ScheduleKey> = UNLOCK_QUEUE.with(|queue| {
+        queue
+            .borrow()
+            .iter()
+            .take_while(|(key, _)| key.due_at <= current_time)
+            .take(UNLOCK_BATCH_SIZE)
+            .map(|(key, _)| key)
+            .collect()
+    });
+
+    for key in due {
+        UNLOCK_QUEUE.with(|queue| queue.borrow_mut().remove(&key));
+
+        CAPSULE_STORAGE.with(|storage| {
+            let mut storage = storage.borrow_mut();
+            if let Some(mut capsule) = storage.get(&key.capsule_id) {
+                if apply_due_transition(&mut capsule, current_time) {
+                    storage.insert(key.capsule_id, capsule.clone());
+                    // Sealed metadata and unlocked text become indexable
+                    index_capsule(&capsule, current_time);
+                }
+            }
+        });
+    }
+
+    // Re-arms immediately if more capsules are already due
+    arm_unlock_timer();
+}
+
+// Move a time-locked capsule to the status due at current_time; returns whether it changed
+fn apply_due_transition(capsule: &mut TimeCapsule, current_time: u64) -> bool {
+    if !matches!(capsule.status, CapsuleStatus::Sealed | CapsuleStatus::UnlockPending) {
+        return false;
+    }
+
+    let mut released = false;
+    let status = if time_lock_passed(capsule, current_time) {
+        status_after_unlock_date(&capsule.access_control)
+    } else if let (Some(switch), Some(last_checkin)) = (&capsule.dead_man_switch, capsule.last_checkin) {
+        let deadline = last_checkin.saturating_add(switch.checkin_interval);
+        if current_time >= deadline.saturating_add(switch.warning_period) {
+            released = true;
+            status_after_unlock_date(&capsule.access_control)
+        } else if current_time >= deadline {
+            CapsuleStatus::UnlockPending
+        } else {
+            CapsuleStatus::Sealed
+        }
+    } else {
+        CapsuleStatus::Sealed
+    };
+
+    if released {
+        capsule.early_unlocked_at = Some(current_time);
+    }
+    let changed = released || status != capsule.status;
+    capsule.status = status;
+    changed
+}
+
+// Conditional capsules stay pending until someone satisfies their conditions
+fn status_after_unlock_date(access_control: &AccessControl) -> CapsuleStatus {
+    match access_control {
+        AccessControl::Public | AccessControl::Private { .. } => CapsuleStatus::Unlocked,
+        AccessControl::Conditional { .. } | AccessControl::ConditionTree { .. } => CapsuleStatus::UnlockPending,
+    }
+}
+
+fn is_valid_coordinate(latitude: f64, longitude: f64) -> bool {
+    (-90.0..=90.0).contains(&latitude) && (-180.0..=180.0).contains(&longitude)
+}
+
+// Remove a capsule from every secondary index; removing entries that were never added is harmless
+fn unindex_capsule(capsule: &TimeCapsule) {
+    unindex_capsule_location(capsule);
+    unindex_capsule_text(capsule);
+    CREATOR_INDEX.with(|index| {
+        index.borrow_mut().remove(&CreatorKey {
+            creator: capsule.creator,
+            capsule_id: capsule.id,
+        })
+    });
+    VIEWER_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for viewer in named_principals(capsule) {
+            index.remove(&ViewerKey { viewer, capsule_id: capsule.id });
+        }
+    });
+    TAG_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for tag in indexed_tags(&capsule.metadata) {
+            index.remove(&TagKey { tag, capsule_id: capsule.id });
+        }
+    });
+    UNLOCK_DATE_INDEX.with(|index| {
+        index.borrow_mut().remove(&UnlockDateKey {
+            unlock_date: capsule.unlock_date,
+            capsule_id: capsule.id,
+        })
+    });
+}
+
+// Add a capsule to every secondary index; sealed locations and tags are left out until the time lock passes
+fn index_capsule(capsule: &TimeCapsule, current_time: u64) {
+    if !metadata_sealed(capsule, &MetadataField::Location, current_time) {
+        index_capsule_location(capsule, current_time);
+    }
+    index_capsule_text(capsule);
+    CREATOR_INDEX.with(|index| {
+        index.borrow_mut().insert(
+            CreatorKey {
+                creator: capsule.creator,
+                capsule_id: capsule.id,
+            },
+            (),
+        )
+    });
+    VIEWER_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for viewer in named_principals(capsule) {
+            index.insert(ViewerKey { viewer, capsule_id: capsule.id }, ());
+        }
+    });
+    if !metadata_sealed(capsule, &MetadataField::Tags, current_time) {
+        TAG_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            for tag in indexed_tags(&capsule.metadata) {
+                index.insert(TagKey { tag, capsule_id: capsule.id }, ());
+            }
+        });
+    }
+    UNLOCK_DATE_INDEX.with(|index| {
+        index.borrow_mut().insert(
+            UnlockDateKey {
+                unlock_date: capsule.unlock_date,
+                capsule_id: capsule.id,
+            },
+            (),
+        )
+    });
+}
+
+// Normalized tags of a capsule that fit an index key; longer ones predate the tag limit
+fn indexed_tags(metadata: &CapsuleMetadata) -> BTreeSet<String> {
+    metadata
+        .tags
+        .iter()
+        .map(|tag| normalize_tag(tag))
+        .filter(|tag| !tag.is_empty() && tag.len() <= MAX_TAG_LENGTH)
+        .collect()
+}
+
+// Only unlocked public capsules are searchable
+fn is_searchable(capsule: &TimeCapsule) -> bool {
+    matches!(capsule.access_control, AccessControl::Public) && capsule.status == CapsuleStatus::Unlocked
+}
+
+// Add a searchable capsule's terms to the search index
+fn index_capsule_text(capsule: &TimeCapsule) {
+    if !is_searchable(capsule) {
+        return;
+    }
+    SEARCH_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for (term, weight) in capsule_terms(capsule) {
+            index.insert(
+                SearchKey {
+                    term,
+                    capsule_id: capsule.id,
+                },
+                weight,
+            );
+        }
+    });
+}
+
+// Remove a capsule's terms from the search index; capsule_terms is deterministic, so they match what was added
+fn unindex_capsule_text(capsule: &TimeCapsule) {
+    if !is_searchable(capsule) {
+        return;
+    }
+    SEARCH_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        for (term, _) in capsule_terms(capsule) {
+            index.remove(&SearchKey {
+                term,
+                capsule_id: capsule.id,
+            });
+        }
+    });
+}
+
+// Weighted terms of a capsule: title words count most, then description and
+// cultural significance, then text content. Only the MAX_INDEXED_TERMS heaviest are kept,
+// so the lightest words of a long capsule don't find it
+fn capsule_terms(capsule: &TimeCapsule) -> Vec<(String, u32)> {
+    let mut weights = BTreeMap::new();
+    let mut add = |text: &str, weight: u32| {
+        for term in tokenize(text) {
+            let entry = weights.entry(term).or_insert(0u32);
+            *entry = entry.saturating_add(weight);
+        }
+    };
+
+    add(&capsule.metadata.title, 4);
+    add(&capsule.metadata.description, 2);
+    if let Some(significance) = &capsule.metadata.cultural_significance {
+        add(significance, 2);
+    }
+    let mut texts = Vec::new();
+    collect_text(&capsule.content, &mut texts);
+    for text in texts {
+        add(text, 1);
+    }
+
+    let mut terms: Vec<(String, u32)> = weights.into_iter().collect();
+    terms.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    terms.truncate(MAX_INDEXED_TERMS);
+    terms
+}
+
+// Plain text of a capsule's content; encrypted, external and committed content has none
+fn collect_text<'a>(content: &'a CapsuleContent, texts: &mut Vec<&'a str>) {
+    match content {
+        CapsuleContent::Text(text) => texts.push(text),
+        CapsuleContent::MultipartMessage { parts, title } => {
+            texts.push(title);
+            for part in parts {
+                collect_text(part, texts);
+            }
+        }
+        _ => {}
+    }
+}
+
+// Split text into lowercase alphanumeric words, skipping words too short or long to index
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .map(str::to_lowercase)
+        .filter(|word| (MIN_TERM_LENGTH..=MAX_TERM_LENGTH).contains(&word.len()))
+}
+
+// Remove a capsule from the geo index
+// Removes both the exact and the coarsened cell, whichever the capsule was indexed under
+fn unindex_capsule_location(capsule: &TimeCapsule) {
+    if let Some(location) = &capsule.metadata.location {
+        let mut cells = vec![geo_cell(location.latitude, location.longitude)];
+        if let Some(step) = location.precision.as_ref().and_then(precision_grid) {
+            let (latitude, longitude) = coarsen_position(location, step);
+            cells.push(geo_cell(latitude, longitude));
+        }
+        GEO_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            for cell in cells {
+                index.remove(&GeoKey {
+                    cell,
+                    capsule_id: capsule.id,
+                });
+            }
+        });
+    }
+}
+
+// Add a capsule to the geo index at its public position, replacing the coarsened entry once it unlocks
+fn index_capsule_location(capsule: &TimeCapsule, current_time: u64) {
+    if let Some(location) = &capsule.metadata.location {
+        unindex_capsule_location(capsule);
+        let (latitude, longitude) = public_position(location, capsule, current_time);
+        GEO_INDEX.with(|index| {
+            index.borrow_mut().insert(
+                GeoKey {
+                    cell: geo_cell(latitude, longitude),
+                    capsule_id: capsule.id,
+                },
+                (),
+            );
+        });
+    }
+}
+
+// Position shown to anyone but the creator: exact once the time lock passes, coarsened before
+fn public_position(location: &GeoLocation, capsule: &TimeCapsule, current_time: u64) -> (f64, f64) {
+    match location.precision.as_ref().and_then(precision_grid) {
+        Some(step) if !time_lock_passed(capsule, current_time) => coarsen_position(location, step),
+        _ => (location.latitude, location.longitude),
+    }
+}
+
+// Location shown to anyone but the creator. While the position is coarsened its name is
+// left out too, since a name usually pinpoints the place the grid is meant to blur
+fn public_location(location: &GeoLocation, capsule: &TimeCapsule, current_time: u64) -> GeoLocation {
+    let (latitude, longitude) = public_position(location, capsule, current_time);
+    let coarsened = location.precision.as_ref().and_then(precision_grid).is_some() && !time_lock_passed(capsule, current_time);
+    GeoLocation {
+        latitude,
+        longitude,
+        location_name: if coarsened { String::new() } else { location.location_name.clone() },
+        precision: location.precision.clone(),
+    }
+}
+
+// Grid step in degrees, or None for exact positions
+fn precision_grid(precision: &LocationPrecision) -> Option<f64> {
+    match precision {
+        LocationPrecision::Exact => None,
+        LocationPrecision::Street => Some(0.001),
+        LocationPrecision::Neighborhood => Some(0.01),
+        LocationPrecision::City => Some(0.1),
+    }
+}
+
+// Center of the grid cell containing the position
+fn coarsen_position(location: &GeoLocation, step: f64) -> (f64, f64) {
+    let snap = |value: f64, bound: f64| (((value / step).floor() + 0.5) * step).clamp(-bound, bound);
+    (snap(location.latitude, 90.0), snap(location.longitude, 180.0))
+}
+
+// Index key ranges, [from, to), of the cell ranges left to scan after the cursor; callers still check the distance
+fn geo_key_ranges(ranges: Vec<(u64, u64)>, start_after: Option<&GeoKey>) -> Vec<(GeoKey, GeoKey)> {
+    ranges
+        .into_iter()
+        .filter_map(|(start, end)| {
+            let mut from = GeoKey { cell: start, capsule_id: 0 };
+            if let Some(after) = start_after {
+                from = from.max(GeoKey {
+                    cell: after.cell,
+                    capsule_id: after.capsule_id.saturating_add(1),
+                });
+            }
+            let to = GeoKey { cell: end, capsule_id: 0 };
+            (from < to).then_some((from, to))
+        })
+        .collect()
+}
+
+// Full-precision cell of a position: longitude and latitude bits interleaved, longitude first
+fn geo_cell(latitude: f64, longitude: f64) -> u64 {
+    interleave_bits(
+        quantize_coordinate(longitude, -180.0, 360.0),
+        quantize_coordinate(latitude, -90.0, 180.0),
+        GEO_INDEX_BITS,
+    )
+}
+
+fn quantize_coordinate(value: f64, min: f64, span: f64) -> u64 {
+    let cells = 1u64 << GEO_INDEX_BITS;
+    // Float to int casts saturate, so values below `min` land in cell 0
+    (((value - min) / span * cells as f64).floor() as u64).min(cells - 1)
+}
+
+fn interleave_bits(longitude_bits: u64, latitude_bits: u64, bits: u32) -> u64 {
+    (0..bits).fold(0, |cell, i| {
+        cell | ((longitude_bits >> i) & 1) << (2 * i + 1) | ((latitude_bits >> i) & 1) << (2 * i)
+    })
+}
+
+// Ranges of full-precision cells, [start, end), covering every point within `radius_km`
+fn geo_cell_ranges(latitude: f64, longitude: f64, radius_km: f64) -> Vec<(u64, u64)> {
+    let angular_radius = radius_km / EARTH_RADIUS_KM;
+    let delta_lat = angular_radius.to_degrees();
+    let mut lat_min = latitude - delta_lat;
+    let mut lat_max = latitude + delta_lat;
+
+    // Longitude spans, split at the antimeridian
+    let lon_spans: Vec<(f64, f64)> = if lat_min <= -90.0 || lat_max >= 90.0 {
+        // The circle contains a pole, so it touches every longitude
+        lat_min = lat_min.max(-90.0);
+        lat_max = lat_max.min(90.0);
+        vec![(-180.0, 180.0)]
+    } else {
+        let ratio = angular_radius.sin() / latitude.to_radians().cos();
+        if ratio >= 1.0 || angular_radius >= std::f64::consts::FRAC_PI_2 {
+            vec![(-180.0, 180.0)]
+        } else {
+            let delta_lon = ratio.asin().to_degrees();
+            let (lon_min, lon_max) = (longitude - delta_lon, longitude + delta_lon);
+            if lon_min < -180.0 {
+                vec![(lon_min + 360.0, 180.0), (-180.0, lon_max)]
+            } else if lon_max > 180.0 {
+                vec![(lon_min, 180.0), (-180.0, lon_max - 360.0)]
+            } else {
+                vec![(lon_min, lon_max)]
+            }
+        }
+    };
+
+    // Cell indices covering [min, max] with `bits` bits per axis
+    let span = |min: f64, max: f64, axis_min: f64, axis_span: f64, bits: u32| {
+        let shift = GEO_INDEX_BITS - bits;
+        (
+            quantize_coordinate(min, axis_min, axis_span) >> shift,
+            quantize_coordinate(max, axis_min, axis_span) >> shift,
+        )
+    };
+
+    // Use the finest precision that keeps the number of scanned cells small
+    let mut bits = GEO_INDEX_BITS;
+    while bits > 0 {
+        let (lat_lo, lat_hi) = span(lat_min, lat_max, -90.0, 180.0, bits);
+        let lon_cells: u64 = lon_spans
+            .iter()
+            .map(|&(min, max)| {
+                let (lo, hi) = span(min, max, -180.0, 360.0, bits);
+                hi - lo + 1
+            })
+            .sum();
+        if (lat_hi - lat_lo + 1) * lon_cells <= MAX_GEO_CELLS {
+            break;
+        }
+        bits -= 1;
+    }
+
+    let shift = 2 * (GEO_INDEX_BITS - bits);
+    let (lat_lo, lat_hi) = span(lat_min, lat_max, -90.0, 180.0, bits);
+    let mut ranges = Vec::new();
+    for lat_cell in lat_lo..=lat_hi {
+        for &(min, max) in &lon_spans {
+            let (lon_lo, lon_hi) = span(min, max, -180.0, 360.0, bits);
+            for lon_cell in lon_lo..=lon_hi {
+                let prefix = interleave_bits(lon_cell, lat_cell, bits);
+                ranges.push((prefix << shift, (prefix + 1) << shift));
+            }
+        }
+    }
+
+    // Merge adjacent ranges to reduce the number of index scans
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+// Helper function to calculate distance between two points
+fn calculate_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    // Haversine formula implementation
+    const R: f64 = EARTH_RADIUS_KM;
+    
+    let lat1_rad = lat1.to_radians();
+    let lat2_rad = lat2.to_radians();
+    let delta_lat = (lat2 - lat1).to_radians();
+    let delta_lon = (lon2 - lon1).to_radians();
+
+    let a = (delta_lat / 2.0).sin().powi(2) +
+        lat1_rad.cos() * lat2_rad.cos() * (delta_lon / 2.0).sin().powi(2);
+    let c = 2.0 * a.sqrt().asin();
+
+    R * c
+}
+
+// Export Candid interface
+ic_cdk::export_candid!();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(byte: u8) -> Principal {
+        Principal::from_slice(&[byte; 10])
+    }
+
+    fn metadata() -> CapsuleMetadata {
+        CapsuleMetadata {
+            title: "Letter".to_string(),
+            description: "For later".to_string(),
+            tags: vec!["family".to_string()],
+            location: None,
+            cultural_significance: None,
+        }
+    }
+
+    fn enveloped(version: u32, payload: Vec<u8>) -> Vec<u8> {
+        let mut bytes = CAPSULE_ENVELOPE_MAGIC.to_vec();
+        bytes.extend_from_slice(&version.to_be_bytes());
+        bytes.extend(payload);
+        bytes
+    }
+
+    fn legacy_capsule(access_control: LegacyAccessControl) -> LegacyTimeCapsule {
+        LegacyTimeCapsule {
+            id: 7,
+            creator: principal(1).to_text(),
+            creation_date: 100,
+            unlock_date: 200,
+            content: CapsuleContentV1::Text("hello".to_string()),
+            access_control,
+            metadata: metadata(),
+            status: CapsuleStatus::Sealed,
+        }
+    }
+
+    fn v2_capsule(creator: String, access_control: AccessControlV2) -> TimeCapsuleV2 {
+        TimeCapsuleV2 {
+            id: 9,
+            creator,
+            creation_date: 100,
+            unlock_date: 200,
+            content: CapsuleContent::Text("hello".to_string()),
+            access_control,
+            metadata: metadata(),
+            status: CapsuleStatus::Sealed,
+            editable_until: Some(150),
+            unlock_date_locked: Some(true),
+            early_unlock_guardians: None,
+            early_unlocked_at: None,
+            dead_man_switch: None,
+            last_checkin: None,
+        }
+    }
+
+    #[test]
+    fn bare_baseline_record_decodes_as_schema_0() {
+        let legacy = legacy_capsule(LegacyAccessControl::Private {
+            allowed_viewers: vec![principal(2).to_text()],
+        });
+
+        let capsule = decode_stored_capsule(&Encode!(&legacy).unwrap()).unwrap();
+
+        assert_eq!(capsule.id, 7);
+        assert_eq!(capsule.creator, principal(1));
+        assert_eq!(capsule.unlock_date, 200);
+        assert!(matches!(capsule.content, CapsuleContent::Text(ref text) if text == "hello"));
+        assert!(matches!(
+            capsule.access_control,
+            AccessControl::Private { ref allowed_viewers } if *allowed_viewers == vec![principal(2)]
+        ));
+        assert_eq!(capsule.metadata.title, "Letter");
+        assert!(capsule.status == CapsuleStatus::Sealed);
+        assert!(capsule.editable_until.is_none());
+        assert!(capsule.sealed_metadata.is_none());
+    }
+
+    // Candid bytes of a TimeCapsule under the baseline definitions, with their own type table:
+    // a multipart message holding text and an EncryptedMessage, a private viewer and a
+    // location without `precision`. Frozen, so later type changes can't make it match
+    const BASELINE_CAPSULE_HEX: &str = concat!(
+        "4449444c106c08dbb70178b2ceef2f04acfdaa8b0171b99adecb0101c991bd960278efcee780040382bd92c807028eb3",
+        "80c20f786b04dba0f69c030ccdf1cbbe0371e7f48eaf070bc390d9dd09096b03a6e6a2a3040fe3a981b6050ec9e99fdc",
+        "097f6c0598abec810171e081e3ac0208d9e9dae70407fc91f4f80571b5dc99aa0e066b04c3e0ffad037fbc8f9ebc097f",
+        "d39be4df097f82e580b90c7f6c03ec8ea33372af82afce0972d5ff95dd0a716e056d716e716c02b99adecb010ac9afa1",
+        "a403716d7b6c02b9b49c7e71b59eb7bd04716c0298abec810171a0a2d6ea0b0d6d016c01caa989aa08076c028ead9306",
+        "719ee6a95b7101000700000000000000011b6b63796f762d6d696261652d61716361692d62616561712d63616900074c",
+        "65747465727302010568656c6c6f030301020302706bc800000000000000064c657474657200010666616d696c790946",
+        "6f72206c617465720176711b0de06d48404260e5d0225b024005506172697301011b6c326867782d6f696361692d6261",
+        "6561712d63616962612d6561716400000000000000",
+    );
+
+    #[test]
+    fn frozen_baseline_bytes_decode_as_schema_0() {
+        let capsule = decode_stored_capsule(&hex::decode(BASELINE_CAPSULE_HEX).unwrap()).unwrap();
+
+        assert_eq!(capsule.id, 7);
+        assert_eq!(capsule.creator, principal(1));
+        assert_eq!(capsule.creation_date, 100);
+        assert_eq!(capsule.unlock_date, 200);
+        let CapsuleContent::MultipartMessage { parts, title } = &capsule.content else {
+            panic!("expected a multipart message");
+        };
+        assert_eq!(title, "Letters");
+        assert!(matches!(&parts[0], CapsuleContent::Text(text) if text == "hello"));
+        assert!(matches!(
+            &parts[1],
+            CapsuleContent::EncryptedMessage(envelope)
+                if envelope.algorithm == EncryptionAlgorithm::Unspecified
+                    && envelope.ciphertext == [1, 2, 3]
+                    && envelope.recipient_public_key == b"pk"
+        ));
+        assert!(matches!(
+            capsule.access_control,
+            AccessControl::Private { ref allowed_viewers } if *allowed_viewers == vec![principal(2)]
+        ));
+        assert_eq!(capsule.metadata.title, "Letter");
+        assert_eq!(capsule.metadata.tags, vec!["family".to_string()]);
+        assert!(capsule.metadata.cultural_significance.is_none());
+        let location = capsule.metadata.location.as_ref().unwrap();
+        assert_eq!((location.latitude, location.longitude), (48.8584, 2.2945));
+        assert_eq!(location.location_name, "Paris");
+        assert!(location.precision.is_none());
+        assert!(capsule.status == CapsuleStatus::Sealed);
+    }
+
+    #[test]
+    fn legacy_token_condition_becomes_typed() {
+        let legacy = legacy_capsule(LegacyAccessControl::Conditional {
+            condition_type: "token_holder".to_string(),
+            condition_data: format!(
+                r#"{{"ledger_canister_id":"{}","min_balance":500,"subaccount":null}}"#,
+                principal(3).to_text()
+            ),
+        });
+
+        let capsule = decode_stored_capsule(&Encode!(&legacy).unwrap()).unwrap();
+
+        match capsule.access_control {
+            AccessControl::Conditional {
+                condition: UnlockCondition::TokenHolder(gate),
+            } => {
+                assert_eq!(gate.ledger_canister_id, principal(3));
+                assert_eq!(gate.min_balance, Nat::from(500u64));
+            }
+            _ => panic!("expected a token gate"),
+        }
+    }
+
+    #[test]
+    fn legacy_unknown_condition_falls_back_to_creator_only() {
+        let legacy = legacy_capsule(LegacyAccessControl::Conditional {
+            condition_type: "secret_handshake".to_string(),
+            condition_data: "{}".to_string(),
+        });
+
+        let capsule = decode_stored_capsule(&Encode!(&legacy).unwrap()).unwrap();
+
+        assert!(matches!(
+            capsule.access_control,
+            AccessControl::Private { ref allowed_viewers } if allowed_viewers.is_empty()
+        ));
+    }
+
+    #[test]
+    fn v1_record_gets_an_encrypted_envelope() {
+        let v1 = TimeCapsuleV1 {
+            id: 8,
+            creator: principal(1).to_text(),
+            creation_date: 100,
+            unlock_date: 200,
+            content: CapsuleContentV1::EncryptedMessage {
+                content: vec![1, 2, 3],
+                public_key: "key".to_string(),
+            },
+            access_control: AccessControlV2::Public,
+            metadata: metadata(),
+            status: CapsuleStatus::Unlocked,
+            editable_until: None,
+            unlock_date_locked: None,
+            early_unlock_guardians: None,
+            early_unlocked_at: Some(180),
+            dead_man_switch: None,
+            last_checkin: None,
+        };
+
+        let capsule = decode_stored_capsule(&enveloped(1, Encode!(&v1).unwrap())).unwrap();
+
+        assert_eq!(capsule.early_unlocked_at, Some(180));
+        match capsule.content {
+            CapsuleContent::EncryptedMessage(envelope) => {
+                assert!(envelope.algorithm == EncryptionAlgorithm::Unspecified);
+                assert_eq!(envelope.ciphertext, vec![1, 2, 3]);
+                assert_eq!(envelope.recipient_public_key, b"key".to_vec());
+                assert_eq!(envelope.content_hash, Sha256::digest([1, 2, 3]).to_vec());
+            }
+            _ => panic!("expected an encrypted envelope"),
+        }
+    }
+
+    #[test]
+    fn v2_record_parses_principals() {
+        let v2 = v2_capsule(
+            principal(1).to_text(),
+            AccessControlV2::Private {
+                allowed_viewers: vec![principal(4).to_text(), "not a principal".to_string(), principal(2).to_text()],
+            },
+        );
+
+        let capsule = decode_stored_capsule(&enveloped(2, Encode!(&v2).unwrap())).unwrap();
+
+        assert_eq!(capsule.creator, principal(1));
+        assert_eq!(capsule.editable_until, Some(150));
+        assert_eq!(capsule.unlock_date_locked, Some(true));
+        // Invalid viewers are dropped and the rest sorted for binary search
+        assert!(matches!(
+            capsule.access_control,
+            AccessControl::Private { ref allowed_viewers } if *allowed_viewers == vec![principal(2), principal(4)]
+        ));
+    }
+
+    #[test]
+    fn v2_record_with_invalid_creator_has_no_owner() {
+        let v2 = v2_capsule("not a principal".to_string(), AccessControlV2::Public);
+
+        let capsule = decode_stored_capsule(&enveloped(2, Encode!(&v2).unwrap())).unwrap();
+
+        assert_eq!(capsule.creator, Principal::management_canister());
+    }
+
+    #[test]
+    fn current_record_round_trips() {
+        let capsule: TimeCapsule = v2_capsule(principal(1).to_text(), AccessControlV2::Public).into();
+        let bytes = capsule.to_bytes();
+
+        assert!(bytes.starts_with(CAPSULE_ENVELOPE_MAGIC));
+        assert_eq!(bytes[4..8], CAPSULE_SCHEMA_VERSION.to_be_bytes());
+        let decoded = decode_stored_capsule(&bytes).unwrap();
+        assert_eq!(decoded.id, capsule.id);
+        assert_eq!(decoded.creator, capsule.creator);
+        assert_eq!(decoded.unlock_date, capsule.unlock_date);
+        assert!(matches!(decoded.access_control, AccessControl::Public));
+    }
+
+    #[test]
+    fn unknown_or_truncated_envelopes_are_rejected() {
+        assert!(decode_stored_capsule(&enveloped(CAPSULE_SCHEMA_VERSION + 1, Vec::new())).is_err());
+        assert!(decode_stored_capsule(b"TCAP\x00\x00").is_err());
+    }
+
+    fn token_gate(min_balance: u64) -> UnlockCondition {
+        UnlockCondition::TokenHolder(TokenGate {
+            ledger_canister_id: principal(9),
+            min_balance: Nat::from(min_balance),
+            subaccount: None,
+        })
+    }
+
+    fn balance_context(balance: u64) -> ConditionContext {
+        let mut context = ConditionContext::default();
+        context.token_balances.insert((principal(9), None), Ok(Nat::from(balance)));
+        context
+    }
+
+    fn evaluate(expr: &ConditionExpr, caller: Principal, context: &ConditionContext) -> Result<(), ConditionFailure> {
+        evaluate_condition_tree(1, expr, &caller, context)
+    }
+
+    #[test]
+    fn token_gate_needs_a_fetched_balance_of_at_least_the_minimum() {
+        let gate = ConditionExpr::Condition(token_gate(100));
+
+        assert!(evaluate(&gate, principal(1), &balance_context(100)).is_ok());
+        assert!(matches!(
+            evaluate(&gate, principal(1), &balance_context(99)),
+            Err(ConditionFailure::Unmet(_))
+        ));
+        // Queries have no balance to check
+        assert!(matches!(
+            evaluate(&gate, principal(1), &ConditionContext::default()),
+            Err(ConditionFailure::Unverified(_))
+        ));
+    }
+
+    #[test]
+    fn quiz_answers_are_hashed_with_salt_after_normalizing() {
+        let hash = hash_quiz_answer(b"salt", "Blue Whale");
+
+        assert_eq!(hash, Sha256::digest(b"saltblue whale").to_vec());
+        assert_eq!(hash, hash_quiz_answer(b"salt", "  blue WHALE\n"));
+        assert_ne!(hash, hash_quiz_answer(b"pepper", "Blue Whale"));
+    }
+
+    #[test]
+    fn quiz_attempts_are_limited_until_passed() {
+        let quiz = QuizChallenge {
+            questions: vec![QuizQuestion {
+                question: "Largest animal?".to_string(),
+                salt: b"salt".to_vec(),
+                answer_hash: hash_quiz_answer(b"salt", "blue whale"),
+            }],
+            max_attempts: 2,
+        };
+        let wrong = vec!["elephant".to_string()];
+        let right = vec!["Blue whale".to_string()];
+
+        let mut progress = QuizProgress::default();
+        assert!(matches!(
+            grade_quiz_attempt(&quiz, &mut progress, &wrong),
+            Err(CapsuleError::IncorrectAnswers { attempts_remaining: 1 })
+        ));
+        assert!(grade_quiz_attempt(&quiz, &mut progress, &right).is_ok());
+        // A passed quiz stays passed without using attempts
+        assert!(grade_quiz_attempt(&quiz, &mut progress, &wrong).is_ok());
+        assert_eq!(progress.attempts, 2);
+
+        let mut progress = QuizProgress::default();
+        for _ in 0..2 {
+            assert!(grade_quiz_attempt(&quiz, &mut progress, &wrong).is_err());
+        }
+        assert!(matches!(
+            grade_quiz_attempt(&quiz, &mut progress, &right),
+            Err(CapsuleError::QuotaExceeded { limit: 2 })
+        ));
+        assert!(!progress.passed);
+    }
+
+    #[test]
+    fn verified_unlocks_expire_and_are_capped_per_capsule() {
+        assert!(record_verified_unlock(1, principal(1), 0).is_ok());
+        assert!(has_verified_unlock(1, &principal(1), VERIFIED_UNLOCK_TTL - 1));
+        assert!(!has_verified_unlock(1, &principal(1), VERIFIED_UNLOCK_TTL));
+        assert!(!has_verified_unlock(2, &principal(1), 0));
+
+        for byte in 2..=MAX_VERIFIED_UNLOCKS as u32 {
+            let caller = Principal::from_slice(&byte.to_be_bytes());
+            assert!(record_verified_unlock(1, caller, 0).is_ok());
+        }
+        assert!(matches!(
+            record_verified_unlock(1, principal(255), 0),
+            Err(CapsuleError::QuotaExceeded { limit }) if limit == MAX_VERIFIED_UNLOCKS as u64
+        ));
+        // Renewing a current pass doesn't need a free slot
+        assert!(record_verified_unlock(1, principal(1), 0).is_ok());
+
+        // Expired passes make room
+        assert!(record_verified_unlock(1, principal(255), VERIFIED_UNLOCK_TTL).is_ok());
+        assert_eq!(VERIFIED_UNLOCKS.with(|unlocks| unlocks.borrow().len()), 1);
+    }
+
+    #[test]
+    fn geo_fence_checks_the_reported_position() {
+        let fence = ConditionExpr::Condition(UnlockCondition::GeoFence(GeoFence {
+            latitude: 48.8584,
+            longitude: 2.2945,
+            radius_km: 1.0,
+        }));
+        let at = |latitude, longitude| ConditionContext {
+            location: Some(GeoPoint { latitude, longitude }),
+            ..ConditionContext::default()
+        };
+
+        assert!(evaluate(&fence, principal(1), &at(48.8600, 2.2950)).is_ok());
+        assert!(matches!(
+            evaluate(&fence, principal(1), &at(48.8738, 2.2950)),
+            Err(ConditionFailure::Unmet(_))
+        ));
+        assert!(matches!(
+            evaluate(&fence, principal(1), &ConditionContext::default()),
+            Err(ConditionFailure::Unverified(_))
+        ));
+    }
+
+    fn viewers(allowed_viewers: Vec<Principal>) -> UnlockCondition {
+        UnlockCondition::Viewers { allowed_viewers }
+    }
+
+    #[test]
+    fn condition_tree_combines_branches() {
+        let member = ConditionExpr::Condition(viewers(vec![principal(1)]));
+        let holder = ConditionExpr::Condition(token_gate(100));
+        let both = ConditionExpr::And(vec![member.clone(), holder.clone()]);
+        let either = ConditionExpr::Or(vec![member.clone(), holder.clone()]);
+
+        assert!(evaluate(&both, principal(1), &balance_context(100)).is_ok());
+        assert!(matches!(
+            evaluate(&both, principal(2), &balance_context(100)),
+            Err(ConditionFailure::Unmet(ref reason)) if reason.starts_with("AND branch 0")
+        ));
+        assert!(evaluate(&either, principal(1), &ConditionContext::default()).is_ok());
+        assert!(evaluate(&either, principal(2), &balance_context(100)).is_ok());
+        assert!(matches!(
+            evaluate(&either, principal(2), &balance_context(0)),
+            Err(ConditionFailure::Unmet(_))
+        ));
+        // An unchecked branch keeps the whole OR unverified rather than unmet
+        assert!(matches!(
+            evaluate(&either, principal(2), &ConditionContext::default()),
+            Err(ConditionFailure::Unverified(_))
+        ));
+    }
+
+    #[test]
+    fn not_only_passes_conditions_that_were_checked_and_unmet() {
+        let not_holder = ConditionExpr::Not(Box::new(ConditionExpr::Condition(token_gate(100))));
+
+        assert!(evaluate(&not_holder, principal(1), &balance_context(0)).is_ok());
+        assert!(matches!(
+            evaluate(&not_holder, principal(1), &balance_context(100)),
+            Err(ConditionFailure::Unmet(_))
+        ));
+        // A balance that wasn't fetched must not count as "not a holder"
+        assert!(matches!(
+            evaluate(&not_holder, principal(1), &ConditionContext::default()),
+            Err(ConditionFailure::Unverified(_))
+        ));
+    }
+
+    fn covers(ranges: &[(u64, u64)], latitude: f64, longitude: f64) -> bool {
+        let cell = geo_cell(latitude, longitude);
+        ranges.iter().any(|&(start, end)| start <= cell && cell < end)
+    }
+
+    #[test]
+    fn geo_ranges_cover_the_search_circle() {
+        let ranges = geo_cell_ranges(48.8584, 2.2945, 1.0);
+
+        assert!(covers(&ranges, 48.8584, 2.2945));
+        assert!(covers(&ranges, 48.8670, 2.2945));
+        assert!(covers(&ranges, 48.8584, 2.3070));
+        assert!(!covers(&ranges, 40.7128, -74.0060));
+        assert!(ranges.len() as u64 <= MAX_GEO_CELLS);
+    }
+
+    #[test]
+    fn geo_ranges_wrap_around_the_antimeridian() {
+        let ranges = geo_cell_ranges(-17.0, 179.99, 10.0);
+
+        assert!(covers(&ranges, -17.0, 179.95));
+        assert!(covers(&ranges, -17.0, -179.95));
+        assert!(!covers(&ranges, -17.0, 0.0));
+        assert!(ranges.len() as u64 <= MAX_GEO_CELLS);
+    }
+
+    #[test]
+    fn geo_ranges_around_a_pole_cover_every_longitude() {
+        for latitude in [89.99, -89.99] {
+            let ranges = geo_cell_ranges(latitude, 0.0, 5.0);
+
+            for longitude in [-179.9, -90.0, 0.0, 90.0, 179.9] {
+                assert!(covers(&ranges, latitude.signum() * 89.995, longitude));
+            }
+            assert!(!covers(&ranges, 0.0, 0.0));
+        }
+
+        // A circle larger than the planet covers everything
+        let ranges = geo_cell_ranges(0.0, 0.0, 25_000.0);
+        assert!(covers(&ranges, 89.9, 179.9));
+        assert!(covers(&ranges, -89.9, -179.9));
+    }
+
+    fn capsule(id: u64) -> TimeCapsule {
+        let mut capsule: TimeCapsule = v2_capsule(principal(1).to_text(), AccessControlV2::Public).into();
+        capsule.id = id;
+        capsule
+    }
+
+    #[test]
+    fn location_pages_resume_inside_the_cursor_cell() {
+        let scans = |start_after: Option<GeoKey>| -> Vec<((u64, u64), (u64, u64))> {
+            geo_key_ranges(vec![(10, 20), (30, 40)], start_after.as_ref())
+                .into_iter()
+                .map(|(from, to)| ((from.cell, from.capsule_id), (to.cell, to.capsule_id)))
+                .collect()
+        };
+
+        assert_eq!(scans(None), vec![((10, 0), (20, 0)), ((30, 0), (40, 0))]);
+        assert_eq!(
+            scans(Some(GeoKey { cell: 15, capsule_id: 7 })),
+            vec![((15, 8), (20, 0)), ((30, 0), (40, 0))]
+        );
+        // Ranges wholly before the cursor are skipped
+        assert_eq!(scans(Some(GeoKey { cell: 35, capsule_id: 7 })), vec![((35, 8), (40, 0))]);
+    }
+
+    // Page through capsules 1..=count the way paginate_capsules does, returning each page's IDs
+    fn pages(count: u64, limit: u32, select: impl Fn(&TimeCapsule) -> bool) -> Vec<(Vec<u64>, Option<u64>)> {
+        let mut pages = Vec::new();
+        let mut start_after = None;
+        loop {
+            let capsules = (1..=count)
+                .filter(|&id| start_after.is_none_or(|after| id > after))
+                .map(|id| (id, capsule(id)));
+            let (items, next_cursor) = paginate(capsules, limit, |capsule| select(&capsule).then_some(capsule.id));
+            pages.push((items, next_cursor));
+            match next_cursor {
+                Some(cursor) => start_after = Some(cursor),
+                None => return pages,
+            }
+        }
+    }
+
+    #[test]
+    fn pages_continue_after_the_cursor_until_none() {
+        assert_eq!(
+            pages(5, 2, |_| true),
+            vec![(vec![1, 2], Some(2)), (vec![3, 4], Some(4)), (vec![5], None)]
+        );
+        // A full last page has no cursor when nothing follows it
+        assert_eq!(pages(4, 2, |_| true), vec![(vec![1, 2], Some(2)), (vec![3, 4], None)]);
+        // Filtered capsules are skipped without ending the page early
+        assert_eq!(
+            pages(6, 2, |capsule| capsule.id % 2 == 0),
+            vec![(vec![2, 4], Some(4)), (vec![6], None)]
+        );
+        // The limit is clamped to 1..=MAX_PAGE_SIZE
+        assert_eq!(pages(2, 0, |_| true), vec![(vec![1], Some(1)), (vec![2], None)]);
+    }
+
+    #[test]
+    fn sparse_pages_stop_at_the_scan_budget() {
+        let count = MAX_PAGE_SCAN as u64 + 1;
+        let pages = pages(count, 10, |capsule| capsule.id == count);
+
+        assert_eq!(pages, vec![(Vec::new(), Some(MAX_PAGE_SCAN as u64)), (vec![count], None)]);
+    }
+
+    #[test]
+    fn large_capsules_are_split_across_pages() {
+        let large = |id| {
+            let mut capsule = capsule(id);
+            capsule.content = CapsuleContent::Text("x".repeat(600 * 1024));
+            (id, capsule)
+        };
+
+        let (items, next_cursor) = paginate((1..=3).map(large), 10, Some);
+        assert_eq!(items.iter().map(|capsule| capsule.id).collect::<Vec<_>>(), vec![1, 2]);
+        assert_eq!(next_cursor, Some(2));
+
+        // A single item larger than the budget still fills a page on its own
+        let mut huge = capsule(1);
+        huge.content = CapsuleContent::Text("x".repeat(MAX_PAGE_BYTES + 1));
+        let (items, next_cursor) = paginate([(1, huge), large(2)].into_iter(), 10, Some);
+        assert_eq!(items.len(), 1);
+        assert_eq!(next_cursor, Some(1));
+    }
+
+    fn envelope(algorithm: EncryptionAlgorithm, key: Vec<u8>, nonce_len: usize) -> EncryptedEnvelope {
+        let ciphertext = vec![5; 48];
+        EncryptedEnvelope {
+            algorithm,
+            key_fingerprint: Sha256::digest(&key).to_vec(),
+            recipient_public_key: key,
+            nonce: vec![0; nonce_len],
+            content_hash: Sha256::digest(&ciphertext).to_vec(),
+            ciphertext,
+            wrapped_keys: None,
+        }
+    }
+
+    #[test]
+    fn unreferenced_blobs_count_against_the_quota_until_they_expire() {
+        let hour = 60 * 60 * 1_000_000_000;
+        let mut blob = BlobRecord {
+            owner: principal(1),
+            size: 10,
+            sha256: vec![0; 32],
+            expires_at: None,
+            finalized: true,
+            capsule_id: None,
+        };
+        release_blob(1, &mut blob, hour);
+        BLOBS.with(|blobs| blobs.borrow_mut().insert(1, blob.clone()));
+        assert_eq!(pending_uploads_of(&principal(1)).len(), 1);
+        assert!(!upload_expired(&blob, hour + UPLOAD_TTL - 1));
+        assert!(upload_expired(&blob, hour + UPLOAD_TTL));
+
+        attach_blobs(7, &[1]);
+        let attached = BLOBS.with(|blobs| blobs.borrow().get(&1)).unwrap();
+        assert!(pending_uploads_of(&principal(1)).is_empty());
+        assert!(!upload_expired(&attached, u64::MAX));
+        assert!(UPLOAD_EXPIRY.with(|expiry| expiry.borrow().is_empty()));
+
+        // An edit that drops the blob starts a fresh expiry period
+        let content = CapsuleContent::Blob {
+            blob_id: 1,
+            media_type: "image/png".to_string(),
+            size: 10,
+            sha256: vec![0; 32],
+        };
+        detach_blobs(&content, 2 * UPLOAD_TTL);
+        let released = BLOBS.with(|blobs| blobs.borrow().get(&1)).unwrap();
+        assert_eq!(pending_uploads_of(&principal(1)).len(), 1);
+        assert_eq!(released.expires_at, Some(3 * UPLOAD_TTL));
+    }
+
+    #[test]
+    fn commitments_hash_the_canonical_content_bytes() {
+        let text = CapsuleContent::Text("hello".to_string());
+        assert_eq!(
+            hex::encode(commitment_hash(&text, b"salt")),
+            "dc15441f127fdf1e3650f058c83ca4f8713ec472d62aa5666662a7fba326dd9d"
+        );
+
+        let album = CapsuleContent::MultipartMessage {
+            parts: vec![
+                text,
+                CapsuleContent::Blob {
+                    blob_id: 1,
+                    media_type: "image/png".to_string(),
+                    size: 10,
+                    sha256: vec![0xab; 32],
+                },
+            ],
+            title: "Album".to_string(),
+        };
+        assert_eq!(
+            hex::encode(commitment_hash(&album, b"salt")),
+            "d73487bd4cd81e7c18ecc3bc02c1c0de0101829d96fa600e0dad0ebfe96b3268"
+        );
+    }
+
+    #[test]
+    fn reveals_must_match_the_commitment_after_the_time_lock() {
+        let content = CapsuleContent::Text("hello".to_string());
+        let mut committed = capsule(1);
+        committed.unlock_date = 100;
+        committed.content = CapsuleContent::Committed {
+            commitment: commitment_hash(&content, b"salt"),
+        };
+
+        assert!(matches!(
+            check_reveal(&committed, &content, b"salt", 99),
+            Err(CapsuleError::StillSealed { unlock_date: 100 })
+        ));
+        assert!(check_reveal(&committed, &content, b"salt", 100).is_ok());
+
+        let other = CapsuleContent::Text("hellO".to_string());
+        assert!(matches!(
+            check_reveal(&committed, &other, b"salt", 100),
+            Err(CapsuleError::InvalidPayload { field, .. }) if field == "content"
+        ));
+        assert!(matches!(
+            check_reveal(&committed, &content, b"pepper", 100),
+            Err(CapsuleError::InvalidPayload { field, .. }) if field == "content"
+        ));
+
+        // A revealed capsule can't be revealed again
+        committed.content = content.clone();
+        assert!(matches!(
+            check_reveal(&committed, &content, b"salt", 100),
+            Err(CapsuleError::InvalidOperation { .. })
+        ));
+    }
+
+    // Save a capsule the way create_time_capsule does, without arming the timer
+    fn store(capsule: &TimeCapsule) {
+        CAPSULE_STORAGE.with(|storage| storage.borrow_mut().insert(capsule.id, capsule.clone()));
+        schedule_capsule(capsule);
+        index_capsule(capsule, capsule.creation_date);
+        record_revision(capsule, Vec::new(), capsule.creation_date);
+    }
+
+    fn revisions(capsule_id: u64) -> Vec<Vec<String>> {
+        CAPSULE_HISTORY.with(|history| {
+            history
+                .borrow()
+                .range(RevisionKey { capsule_id, revision: 0 }..)
+                .take_while(|(key, _)| key.capsule_id == capsule_id)
+                .map(|(_, revision)| revision.changed_fields)
+                .collect()
+        })
+    }
+
+    #[test]
+    fn only_the_creator_edits_or_cancels_during_the_grace_period() {
+        let stored = capsule(1);
+        store(&stored);
+        let edit = || UpdateCapsulePayload {
+            content: Some(CapsuleContent::Text("updated".to_string())),
+            unlock_date: Some(300),
+            access_control: None,
+            metadata: None,
+            sealed_metadata: None,
+        };
+
+        assert!(matches!(edit_capsule(1, edit(), principal(2), 120), Err(CapsuleError::AccessDenied)));
+        assert!(matches!(edit_capsule(1, edit(), principal(1), 150), Err(CapsuleError::InvalidOperation { .. })));
+        assert!(matches!(
+            edit_capsule(1, UpdateCapsulePayload { content: None, unlock_date: None, ..edit() }, principal(1), 120),
+            Err(CapsuleError::InvalidPayload { field, .. }) if field == "payload"
+        ));
+        assert!(matches!(delete_capsule(1, principal(2), 120), Err(CapsuleError::AccessDenied)));
+        assert!(matches!(delete_capsule(1, principal(1), 150), Err(CapsuleError::InvalidOperation { .. })));
+
+        // Rejected calls leave the capsule as it was
+        assert_eq!(CAPSULE_STORAGE.with(|storage| storage.borrow().get(&1)).map(|c| c.unlock_date), Some(200));
+        assert_eq!(revisions(1).len(), 1);
+
+        assert!(edit_capsule(1, edit(), principal(1), 120).is_ok());
+        let updated = CAPSULE_STORAGE.with(|storage| storage.borrow().get(&1)).unwrap();
+        assert!(matches!(&updated.content, CapsuleContent::Text(text) if text == "updated"));
+        assert_eq!(updated.unlock_date, 300);
+        assert_eq!(revisions(1), vec![vec![], vec!["content".to_string(), "unlock_date".to_string()]]);
+        let queued: Vec<u64> = UNLOCK_QUEUE.with(|queue| queue.borrow().iter().map(|(key, _)| key.due_at).collect());
+        assert_eq!(queued, vec![300]);
+
+        assert!(delete_capsule(1, principal(1), 120).is_ok());
+        assert!(CAPSULE_STORAGE.with(|storage| storage.borrow().get(&1)).is_none());
+        assert!(revisions(1).is_empty());
+        assert!(UNLOCK_QUEUE.with(|queue| queue.borrow().is_empty()));
+        assert!(matches!(delete_capsule(1, principal(1), 120), Err(CapsuleError::NotFound)));
+    }
+
+    #[test]
+    fn unlock_dates_only_move_forward_while_sealed() {
+        let mut stored = capsule(1);
+        stored.unlock_date_locked = None;
+        store(&stored);
+
+        assert!(matches!(postpone_unlock_date(1, 300, principal(2), 160), Err(CapsuleError::AccessDenied)));
+        for new_date in [150, 200] {
+            assert!(matches!(
+                postpone_unlock_date(1, new_date, principal(1), 160),
+                Err(CapsuleError::InvalidPayload { field, .. }) if field == "new_date"
+            ));
+        }
+        assert!(matches!(postpone_unlock_date(1, 300, principal(1), 200), Err(CapsuleError::InvalidOperation { .. })));
+
+        assert!(postpone_unlock_date(1, 300, principal(1), 160).is_ok());
+        assert_eq!(CAPSULE_STORAGE.with(|storage| storage.borrow().get(&1)).map(|c| c.unlock_date), Some(300));
+        assert_eq!(revisions(1).last(), Some(&vec!["unlock_date".to_string()]));
+        let queued: Vec<u64> = UNLOCK_QUEUE.with(|queue| queue.borrow().iter().map(|(key, _)| key.due_at).collect());
+        assert_eq!(queued, vec![300]);
+
+        // Dates locked at creation or bound into a vetKD key stay put
+        let mut locked = capsule(2);
+        locked.unlock_date_locked = Some(true);
+        store(&locked);
+        assert!(matches!(postpone_unlock_date(2, 300, principal(1), 160), Err(CapsuleError::InvalidOperation { .. })));
+
+        let mut bound = capsule(3);
+        bound.unlock_date_locked = None;
+        bound.content = CapsuleContent::EncryptedMessage(envelope(EncryptionAlgorithm::VetKdIbe, vec![1; 96], 0));
+        store(&bound);
+        assert!(matches!(postpone_unlock_date(3, 300, principal(1), 160), Err(CapsuleError::InvalidOperation { .. })));
+    }
+
+    #[test]
+    fn guardian_quorums_count_current_approvals() {
+        let quorum = GuardianQuorum {
+            threshold: 2,
+            guardians: vec![principal(2), principal(3), principal(4)],
+        };
+        let guardians = ConditionExpr::Condition(UnlockCondition::Guardians(quorum.clone()));
+        let mut gated = capsule(1);
+        gated.early_unlock_guardians = Some(quorum.clone());
+        gated.access_control = AccessControl::Conditional {
+            condition: UnlockCondition::Guardians(quorum),
+        };
+        store(&gated);
+        let status = || CAPSULE_STORAGE.with(|storage| storage.borrow().get(&1)).map(|c| (c.status, c.early_unlocked_at));
+        let condition = || evaluate(&guardians, principal(5), &ConditionContext::default());
+
+        assert!(matches!(record_early_unlock_approval(1, principal(5), 120), Err(CapsuleError::AccessDenied)));
+        assert!(matches!(record_condition_approval(1, principal(5), 120), Err(CapsuleError::AccessDenied)));
+        assert!(matches!(withdraw_approvals(1, principal(2)), Err(CapsuleError::InvalidOperation { .. })));
+
+        // A revoked approval no longer counts towards the threshold
+        assert!(matches!(record_early_unlock_approval(1, principal(2), 120), Ok(false)));
+        assert!(withdraw_approvals(1, principal(2)).is_ok());
+        assert!(matches!(record_early_unlock_approval(1, principal(3), 130), Ok(false)));
+        assert!(matches!(status(), Some((CapsuleStatus::Sealed, None))));
+
+        assert!(matches!(record_early_unlock_approval(1, principal(2), 140), Ok(true)));
+        assert!(matches!(status(), Some((CapsuleStatus::UnlockPending, Some(140)))));
+        assert!(UNLOCK_QUEUE.with(|queue| queue.borrow().is_empty()));
+
+        // Reaching the quorum is final
+        assert!(withdraw_approvals(1, principal(2)).is_ok());
+        assert!(matches!(status(), Some((CapsuleStatus::UnlockPending, Some(140)))));
+        assert!(matches!(record_early_unlock_approval(1, principal(2), 150), Err(CapsuleError::InvalidOperation { .. })));
+
+        // Condition approvals are counted on their own and can still be withdrawn
+        assert!(record_condition_approval(1, principal(2), 150).is_ok());
+        assert!(matches!(condition(), Err(ConditionFailure::Unmet(_))));
+        assert!(record_condition_approval(1, principal(4), 150).is_ok());
+        assert!(condition().is_ok());
+        assert!(withdraw_approvals(1, principal(4)).is_ok());
+        assert!(matches!(condition(), Err(ConditionFailure::Unmet(_))));
+    }
+
+    #[test]
+    fn dead_man_switches_warn_then_release_unless_checked_in() {
+        let mut switched = capsule(1);
+        switched.unlock_date = 1000;
+        switched.dead_man_switch = Some(DeadManSwitch {
+            checkin_interval: 100,
+            warning_period: 50,
+        });
+        switched.last_checkin = Some(100);
+        assert_eq!(scheduled_times(&switched), vec![1000, 200, 250]);
+
+        let mut due = switched.clone();
+        assert!(!apply_due_transition(&mut due, 199));
+        assert!(matches!(due.status, CapsuleStatus::Sealed));
+        assert!(apply_due_transition(&mut due, 200));
+        assert!(matches!(due.status, CapsuleStatus::UnlockPending));
+        assert!(!apply_due_transition(&mut due, 249));
+        assert!(apply_due_transition(&mut due, 250));
+        assert!(matches!(due.status, CapsuleStatus::Unlocked));
+        assert_eq!(due.early_unlocked_at, Some(250));
+
+        // Checking in during the warning window seals the capsule again and moves the deadlines
+        switched.status = CapsuleStatus::UnlockPending;
+        store(&switched);
+        assert!(matches!(record_checkin(1, principal(2), 220), Err(CapsuleError::AccessDenied)));
+        assert!(matches!(record_checkin(1, principal(1), 220), Ok(320)));
+        let checked_in = CAPSULE_STORAGE.with(|storage| storage.borrow().get(&1)).unwrap();
+        assert!(matches!(checked_in.status, CapsuleStatus::Sealed));
+        let queued: Vec<u64> = UNLOCK_QUEUE.with(|queue| queue.borrow().iter().map(|(key, _)| key.due_at).collect());
+        assert_eq!(queued, vec![320, 370, 1000]);
+
+        let mut released = checked_in;
+        assert!(apply_due_transition(&mut released, 370));
+        CAPSULE_STORAGE.with(|storage| storage.borrow_mut().insert(1, released));
+        assert!(matches!(record_checkin(1, principal(1), 380), Err(CapsuleError::InvalidOperation { .. })));
+    }
+
+    // Entries the secondary indexes hold for one capsule
+    #[derive(Debug, Default, PartialEq)]
+    struct IndexEntries {
+        creators: Vec<Principal>,
+        tags: Vec<String>,
+        unlock_dates: Vec<u64>,
+        viewers: Vec<Principal>,
+        cells: Vec<u64>,
+    }
+
+    fn index_entries(capsule_id: u64) -> IndexEntries {
+        let creators = CREATOR_INDEX.with(|index| {
+            index.borrow().iter().filter(|(key, _)| key.capsule_id == capsule_id).map(|(key, _)| key.creator).collect()
+        });
+        let tags = TAG_INDEX.with(|index| {
+            index.borrow().iter().filter(|(key, _)| key.capsule_id == capsule_id).map(|(key, _)| key.tag).collect()
+        });
+        let unlock_dates = UNLOCK_DATE_INDEX.with(|index| {
+            index.borrow().iter().filter(|(key, _)| key.capsule_id == capsule_id).map(|(key, _)| key.unlock_date).collect()
+        });
+        let viewers = VIEWER_INDEX.with(|index| {
+            index.borrow().iter().filter(|(key, _)| key.capsule_id == capsule_id).map(|(key, _)| key.viewer).collect()
+        });
+        let cells = GEO_INDEX.with(|index| {
+            index.borrow().iter().filter(|(key, _)| key.capsule_id == capsule_id).map(|(key, _)| key.cell).collect()
+        });
+        IndexEntries {
+            creators,
+            tags,
+            unlock_dates,
+            viewers,
+            cells,
+        }
+    }
+
+    #[test]
+    fn indexes_follow_updates_and_cancellation() {
+        let mut indexed = capsule(1);
+        indexed.access_control = AccessControl::Private {
+            allowed_viewers: vec![principal(2)],
+        };
+        indexed.metadata.location = Some(location(48.85, 2.35, None));
+        store(&indexed);
+        assert_eq!(
+            index_entries(1),
+            IndexEntries {
+                creators: vec![principal(1)],
+                tags: vec!["family".to_string()],
+                unlock_dates: vec![200],
+                viewers: vec![principal(2)],
+                cells: vec![geo_cell(48.85, 2.35)],
+            }
+        );
+
+        let metadata = CapsuleMetadata {
+            tags: vec!["travel".to_string()],
+            location: Some(location(40.7, -74.0, None)),
+            ..metadata()
+        };
+        let edit = UpdateCapsulePayload {
+            content: None,
+            unlock_date: Some(300),
+            access_control: Some(AccessControl::Private {
+                allowed_viewers: vec![principal(3)],
+            }),
+            metadata: Some(metadata),
+            sealed_metadata: None,
+        };
+        assert!(edit_capsule(1, edit, principal(1), 120).is_ok());
+        assert_eq!(
+            index_entries(1),
+            IndexEntries {
+                creators: vec![principal(1)],
+                tags: vec!["travel".to_string()],
+                unlock_dates: vec![300],
+                viewers: vec![principal(3)],
+                cells: vec![geo_cell(40.7, -74.0)],
+            }
+        );
+
+        assert!(delete_capsule(1, principal(1), 120).is_ok());
+        assert_eq!(index_entries(1), IndexEntries::default());
+    }
+
+    fn searchable(id: u64, title: &str, description: &str) -> TimeCapsule {
+        let mut found = capsule(id);
+        found.metadata = CapsuleMetadata {
+            title: title.to_string(),
+            description: description.to_string(),
+            ..metadata()
+        };
+        found.status = CapsuleStatus::Unlocked;
+        CAPSULE_STORAGE.with(|storage| storage.borrow_mut().insert(id, found.clone()));
+        index_capsule_text(&found);
+        found
+    }
+
+    fn search_pages(query: &str, limit: u32) -> Vec<Vec<(u64, u32)>> {
+        let mut pages = Vec::new();
+        let mut start_after = None;
+        loop {
+            let page = search_index(query, SearchPageRequest { start_after, limit }, &principal(5));
+            pages.push(page.items.iter().map(|hit| (hit.capsule.id, hit.score)).collect());
+            match page.next_cursor {
+                Some(cursor) => start_after = Some(cursor),
+                None => return pages,
+            }
+        }
+    }
+
+    #[test]
+    fn search_ranks_by_score_and_pages_through_every_match() {
+        searchable(1, "Harvest festival", "");
+        searchable(2, "Letter", "harvest festival");
+        searchable(3, "Harvest", "");
+        searchable(4, "Harvest", "village festival");
+        // Only public capsules are indexed
+        let mut private = capsule(5);
+        private.metadata.title = "Harvest festival".to_string();
+        private.status = CapsuleStatus::Unlocked;
+        private.access_control = AccessControl::Private {
+            allowed_viewers: vec![principal(2)],
+        };
+        index_capsule_text(&private);
+
+        // Title words weigh 4, description words 2; capsules missing a word don't match
+        assert_eq!(search_pages("festival HARVEST", 2), vec![vec![(1, 8), (4, 6)], vec![(2, 4)]]);
+        assert_eq!(search_pages("harvest", 10), vec![vec![(1, 4), (3, 4), (4, 4), (2, 2)]]);
+        assert_eq!(search_pages("a", 10), vec![Vec::new()]);
+    }
+
+    #[test]
+    fn search_ranks_within_each_batch_of_candidates() {
+        // Postings without a stored capsule are scanned but never returned
+        let last = MAX_PAGE_SCAN as u64;
+        SEARCH_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+            for capsule_id in 1..=last {
+                index.insert(SearchKey { term: "tide".to_string(), capsule_id }, 1);
+            }
+        });
+        searchable(1, "", "tide");
+        searchable(last + 1, "Tide", "");
+
+        // The better match sits in the second batch, so it comes after the first batch's hit
+        assert_eq!(search_pages("tide", 10), vec![vec![(1, 2)], vec![(last + 1, 4)]]);
+    }
+
+    #[test]
+    fn sealed_metadata_is_redacted_for_others_until_unlock() {
+        let mut sealed = capsule(1);
+        sealed.metadata.location = Some(location(48.85, 2.35, None));
+        sealed.sealed_metadata = Some(vec![MetadataField::Title, MetadataField::Tags, MetadataField::Location]);
+        assert!(metadata_sealed(&sealed, &MetadataField::Title, 199));
+        assert!(!metadata_sealed(&sealed, &MetadataField::Description, 199));
+        assert!(!metadata_sealed(&sealed, &MetadataField::Title, 200));
+
+        let summary = capsule_summary(&sealed, &principal(2), 120);
+        assert!(summary.title.is_empty() && summary.tags.is_empty() && summary.location.is_none());
+        assert!(summary.hidden_fields == vec![MetadataField::Title, MetadataField::Tags, MetadataField::Location]);
+
+        let own = capsule_summary(&sealed, &principal(1), 120);
+        assert_eq!((own.title.as_str(), own.tags.len()), ("Letter", 1));
+        assert!(own.location.is_some() && own.hidden_fields.is_empty());
+
+        let unlocked = capsule_summary(&sealed, &principal(2), 200);
+        assert_eq!((unlocked.title.as_str(), unlocked.tags.len()), ("Letter", 1));
+        assert!(unlocked.location.is_some() && unlocked.hidden_fields.is_empty());
+
+        // Sealed tags and locations stay out of the indexes until the capsule unlocks
+        store(&sealed);
+        let entries = index_entries(1);
+        assert!(entries.tags.is_empty() && entries.cells.is_empty());
+        index_capsule(&sealed, 200);
+        let entries = index_entries(1);
+        assert_eq!((entries.tags, entries.cells), (vec!["family".to_string()], vec![geo_cell(48.85, 2.35)]));
+    }
+
+    #[test]
+    fn well_formed_envelopes_are_accepted() {
+        assert!(validate_envelope(&envelope(EncryptionAlgorithm::X25519XChaCha20Poly1305, vec![1; 32], 24)).is_ok());
+        let mut sec1 = vec![0x02];
+        sec1.extend([1; 32]);
+        assert!(validate_envelope(&envelope(EncryptionAlgorithm::P256Aes256Gcm, sec1, 12)).is_ok());
+        assert!(validate_envelope(&envelope(EncryptionAlgorithm::VetKdIbe, vec![1; 96], 0)).is_ok());
+    }
+
+    #[test]
+    fn malformed_envelopes_are_rejected() {
+        let valid = || envelope(EncryptionAlgorithm::X25519XChaCha20Poly1305, vec![1; 32], 24);
+        let rejects = |envelope: EncryptedEnvelope| validate_envelope(&envelope).is_err();
+
+        assert!(rejects(envelope(EncryptionAlgorithm::X25519XChaCha20Poly1305, vec![1; 33], 24)));
+        assert!(rejects(envelope(EncryptionAlgorithm::P256Aes256Gcm, vec![0x04; 33], 12)));
+        assert!(rejects(envelope(EncryptionAlgorithm::X25519XChaCha20Poly1305, vec![1; 32], 12)));
+        assert!(rejects(envelope(EncryptionAlgorithm::Unspecified, vec![1; 32], 24)));
+
+        let mut wrong_fingerprint = valid();
+        wrong_fingerprint.key_fingerprint[0] ^= 1;
+        assert!(rejects(wrong_fingerprint));
+
+        let mut no_tag = valid();
+        no_tag.ciphertext.truncate(15);
+        no_tag.content_hash = Sha256::digest(&no_tag.ciphertext).to_vec();
+        assert!(rejects(no_tag));
+
+        let mut wrong_hash = valid();
+        wrong_hash.ciphertext[0] ^= 1;
+        assert!(rejects(wrong_hash));
+    }
+
+    fn wrapped_key(recipient: Principal) -> WrappedKey {
+        WrappedKey {
+            recipient,
+            key_fingerprint: vec![1; 32],
+            wrapped_key: vec![2; 48],
+        }
+    }
+
+    #[test]
+    fn wrapped_keys_name_each_viewer_once() {
+        let valid = || envelope(EncryptionAlgorithm::X25519XChaCha20Poly1305, vec![1; 32], 24);
+
+        let mut shared = valid();
+        shared.wrapped_keys = Some(vec![wrapped_key(principal(1)), wrapped_key(principal(2))]);
+        assert!(validate_envelope(&shared).is_ok());
+
+        let mut duplicate_viewer = valid();
+        duplicate_viewer.wrapped_keys = Some(vec![wrapped_key(principal(1)), wrapped_key(principal(1))]);
+        assert!(validate_envelope(&duplicate_viewer).is_err());
+
+        let mut no_viewers = valid();
+        no_viewers.wrapped_keys = Some(Vec::new());
+        assert!(validate_envelope(&no_viewers).is_err());
+
+        let mut empty_key = valid();
+        let mut key = wrapped_key(principal(1));
+        key.wrapped_key.clear();
+        empty_key.wrapped_keys = Some(vec![key]);
+        assert!(validate_envelope(&empty_key).is_err());
+    }
+
+    fn location(latitude: f64, longitude: f64, precision: Option<LocationPrecision>) -> GeoLocation {
+        GeoLocation {
+            latitude,
+            longitude,
+            location_name: "Somewhere".to_string(),
+            precision,
+        }
+    }
+
+    fn assert_position(actual: (f64, f64), expected: (f64, f64)) {
+        assert!(
+            (actual.0 - expected.0).abs() < 1e-9 && (actual.1 - expected.1).abs() < 1e-9,
+            "{:?} is not {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn positions_snap_to_the_center_of_their_cell() {
+        assert_position(coarsen_position(&location(48.8584, 2.2945, None), 0.1), (48.85, 2.25));
+        assert_position(coarsen_position(&location(48.8584, 2.2945, None), 0.001), (48.8585, 2.2945));
+        // Negative coordinates round down too, so the cell never straddles zero
+        assert_position(coarsen_position(&location(-0.04, -0.04, None), 0.1), (-0.05, -0.05));
+        // Cell centers past the poles or the antimeridian are clamped
+        assert_position(coarsen_position(&location(90.0, 180.0, None), 0.1), (90.0, 180.0));
+    }
+
+    #[test]
+    fn others_see_the_coarsened_position_until_unlock() {
+        let capsule = capsule(1);
+        let city = location(48.8584, 2.2945, Some(LocationPrecision::City));
+
+        assert_position(public_position(&city, &capsule, capsule.unlock_date - 1), (48.85, 2.25));
+        assert_position(public_position(&city, &capsule, capsule.unlock_date), (48.8584, 2.2945));
+        let exact = location(48.8584, 2.2945, Some(LocationPrecision::Exact));
+        assert_position(public_position(&exact, &capsule, 0), (48.8584, 2.2945));
+        assert_position(public_position(&location(48.8584, 2.2945, None), &capsule, 0), (48.8584, 2.2945));
+    }
+
+    #[test]
+    fn location_names_are_hidden_while_the_position_is_coarsened() {
+        let capsule = capsule(1);
+        let city = location(48.8584, 2.2945, Some(LocationPrecision::City));
+
+        assert_eq!(public_location(&city, &capsule, capsule.unlock_date - 1).location_name, "");
+        assert_eq!(public_location(&city, &capsule, capsule.unlock_date).location_name, "Somewhere");
+        let exact = location(48.8584, 2.2945, Some(LocationPrecision::Exact));
+        assert_eq!(public_location(&exact, &capsule, 0).location_name, "Somewhere");
+
+        let summary = capsule_summary(
+            &TimeCapsule {
+                metadata: CapsuleMetadata {
+                    location: Some(city),
+                    ..metadata()
+                },
+                ..capsule.clone()
+            },
+            &principal(2),
+            capsule.unlock_date - 1,
+        );
+        assert_eq!(summary.location.map(|location| location.location_name), Some(String::new()));
+    }
+
+    #[test]
+    fn listings_open_capsules_for_recent_unlocks_and_hide_unknown_ones() {
+        let mut gated = capsule(1);
+        gated.access_control = AccessControl::ConditionTree {
+            root: ConditionExpr::And(vec![
+                ConditionExpr::Condition(viewers(vec![principal(2)])),
+                ConditionExpr::Condition(token_gate(100)),
+            ]),
+        };
+        let now = gated.unlock_date;
+
+        assert!(matches!(capsule_view(gated.clone(), &principal(2), now), Some(CapsuleView::Summary(_))));
+        assert!(capsule_view(gated.clone(), &principal(3), now).is_none());
+
+        assert!(record_verified_unlock(1, principal(2), now).is_ok());
+        assert!(matches!(capsule_view(gated.clone(), &principal(2), now), Some(CapsuleView::Full(_))));
+        assert!(matches!(
+            capsule_view(gated, &principal(2), now + VERIFIED_UNLOCK_TTL),
+            Some(CapsuleView::Summary(_))
+        ));
+    }
+
+    #[test]
+    fn viewer_index_follows_the_named_principals() {
+        let mut shared = capsule(1);
+        shared.access_control = AccessControl::Private {
+            allowed_viewers: vec![principal(1), principal(2)],
+        };
+        shared.early_unlock_guardians = Some(GuardianQuorum {
+            threshold: 1,
+            guardians: vec![principal(3)],
+        });
+        assert_eq!(
+            named_principals(&shared).into_iter().collect::<Vec<_>>(),
+            vec![principal(2), principal(3)]
+        );
+
+        let indexed = |viewer: Principal| VIEWER_INDEX.with(|index| index.borrow().contains_key(&ViewerKey { viewer, capsule_id: 1 }));
+        index_capsule(&shared, shared.creation_date);
+        assert!(indexed(principal(2)) && indexed(principal(3)) && !indexed(principal(1)));
+
+        unindex_capsule(&shared);
+        assert!(!indexed(principal(2)) && !indexed(principal(3)));
+    }
+
+    #[test]
+    fn key_derivations_are_limited_per_caller_and_in_total() {
+        let mut window = DerivationWindow::default();
+        let hour = KEY_DERIVATION_WINDOW;
+
+        for _ in 0..MAX_KEY_DERIVATIONS_PER_CALLER {
+            assert!(record_key_derivation(&mut window, principal(1), hour).is_ok());
+        }
+        assert_eq!(
+            record_key_derivation(&mut window, principal(1), hour),
+            Err(MAX_KEY_DERIVATIONS_PER_CALLER.into())
+        );
+
+        // Enough other callers to use up the rest of the canister's budget
+        for byte in 2..=(MAX_KEY_DERIVATIONS / MAX_KEY_DERIVATIONS_PER_CALLER) as u8 {
+            for _ in 0..MAX_KEY_DERIVATIONS_PER_CALLER {
+                assert!(record_key_derivation(&mut window, principal(byte), hour).is_ok());
+            }
+        }
+        assert_eq!(
+            record_key_derivation(&mut window, principal(255), hour),
+            Err(MAX_KEY_DERIVATIONS.into())
+        );
+        assert!(!window.by_caller.contains_key(&principal(255)));
+
+        // A failed derivation is given back, but not to a window that has ended
+        release_key_derivation(&mut window, principal(1), hour);
+        assert!(record_key_derivation(&mut window, principal(1), hour).is_ok());
+        assert_eq!(window.total, MAX_KEY_DERIVATIONS);
+
+        // The next window starts from zero
+        assert_eq!(record_key_derivation(&mut window, principal(1), 2 * hour), Ok(2 * hour));
+        release_key_derivation(&mut window, principal(1), hour);
+        assert_eq!(window.total, 1);
+    }
+
+    #[cfg(feature = "mock-vetkd")]
+    fn resolve<T>(future: impl std::future::Future<Output = T>) -> T {
+        use std::task::{Context, Poll, Waker};
+
+        let mut future = std::pin::pin!(future);
+        match future.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(value) => value,
+            Poll::Pending => panic!("mock vetKD calls complete immediately"),
+        }
+    }
+
+    #[cfg(feature = "mock-vetkd")]
+    #[test]
+    fn mock_vetkd_keys_are_deterministic_per_input_and_transport_key() {
+        let transport_key = vec![7; 48];
+        let derive = |input: Vec<u8>, transport_key: Vec<u8>| resolve(derive_vetkd_key(input, transport_key)).unwrap();
+
+        let mut capsule: TimeCapsule = v2_capsule(principal(1).to_text(), AccessControlV2::Public).into();
+        let key = derive(vetkd_input(&capsule), transport_key.clone());
+        assert_eq!(key.len(), 192);
+        assert_eq!(key, derive(vetkd_input(&capsule), transport_key.clone()));
+        assert_ne!(key, derive(vetkd_input(&capsule), vec![8; 48]));
+
+        // The unlock date is part of the identity, so postponing it changes the key
+        capsule.unlock_date += 1;
+        assert_ne!(key, derive(vetkd_input(&capsule), transport_key));
+
+        assert_eq!(resolve(fetch_vetkd_public_key()).unwrap().len(), 96);
+    }
+}
@@ -0,0 +1,51 @@
+#[macro_use]
+extern crate serde;
+use candid::{Nat, Principal};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+// ICRC-1 account; an unset subaccount is the all-zero default subaccount
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+thread_local! {
+    // Balances set by tests; they live on the heap and reset on upgrade
+    static BALANCES: RefCell<BTreeMap<(Principal, Vec<u8>), Nat>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+fn balance_key(account: &Account) -> (Principal, Vec<u8>) {
+    (account.owner, account.subaccount.clone().unwrap_or_else(|| vec![0; 32]))
+}
+
+// Set the balance of an account, read by both balance methods
+#[ic_cdk::update]
+fn set_balance(account: Account, balance: Nat) {
+    BALANCES.with(|balances| balances.borrow_mut().insert(balance_key(&account), balance));
+}
+
+// ICRC-1 balance of an account, zero if never set
+#[ic_cdk::query]
+fn icrc1_balance_of(account: Account) -> Nat {
+    balance_of(&account)
+}
+
+// ICRC-7 token counts of the accounts, from the same balances
+#[ic_cdk::query]
+fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<Nat> {
+    accounts.iter().map(balance_of).collect()
+}
+
+fn balance_of(account: &Account) -> Nat {
+    BALANCES.with(|balances| {
+        balances
+            .borrow()
+            .get(&balance_key(account))
+            .cloned()
+            .unwrap_or_else(|| Nat::from(0u64))
+    })
+}
+
+ic_cdk::export_candid!();